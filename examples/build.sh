@@ -1,6 +1,8 @@
 #!/usr/bin/bash
 
-for file in demo fwht styles 
+for file in demo fwht styles containers
 do
     pandoc $file.md --filter ../target/debug/just-latex -o $file.html
 done
+
+(cd book && mdbook build)
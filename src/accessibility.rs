@@ -0,0 +1,116 @@
+//! Text alternatives of math fragments for screen readers.
+//!
+//! Rendered fragments are images, so all a screen reader gets is what we put in their labels. By
+//! default that is the TeX source, backslashes and all. Two things can be done better:
+//!
+//! - speech text, either a simplified reading of the source or the output of an external command
+//!   such as the Speech Rule Engine;
+//! - MathML from an external converter (e.g. `latexmlmath`), put in the document next to the
+//!   images but visually hidden, while the images themselves are hidden from screen readers.
+//!
+//! External commands get the TeX source of a fragment on stdin and print the result on stdout.
+//!
+//! Raw LaTeX blocks are pictures or whatever else the document typesets, rather than math, so
+//! neither applies to them: they get a neutral label (see [`describe_raw_block`]).
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::config::{AccessibilityConfig, Speech};
+
+/// Text alternatives of a fragment.
+pub struct Description {
+    /// What screen readers say for the fragment.
+    pub label: String,
+    /// MathML markup of the fragment, if a converter is configured.
+    pub mathml: Option<String>,
+}
+
+/// Describes a raw LaTeX block, such as a TikZ picture. Reading out its source would not help.
+pub fn describe_raw_block() -> Description {
+    Description {
+        label: "LaTeX figure".into(),
+        mathml: None,
+    }
+}
+
+/// Describes a math fragment.
+pub fn describe(config: &AccessibilityConfig, src: &str) -> Result<Description> {
+    let label = match config.speech {
+        Speech::Source => src.into(),
+        Speech::Plain => plain_speech(src),
+        Speech::Command => run(&config.speech_command, src)?.trim().into(),
+    };
+    let mathml = if config.mathml_command.is_empty() {
+        None
+    } else {
+        Some(run(&config.mathml_command, src)?.trim().into())
+    };
+    Ok(Description { label, mathml })
+}
+
+/// Spells the source in a way that is less painful to listen to: control words lose their
+/// backslash, `^` and `_` are read as superscript and subscript, and grouping characters are
+/// dropped.
+fn plain_speech(src: &str) -> String {
+    let mut speech = String::new();
+    let mut chars = src.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_alphabetic() {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                if word.is_empty() {
+                    // A control symbol such as \{ or \,: keep the symbol only if it is visible.
+                    match chars.next() {
+                        Some(c) if c.is_ascii_punctuation() && !",;:!".contains(c) => {
+                            speech.push(c)
+                        }
+                        _ => speech.push(' '),
+                    }
+                } else {
+                    speech.push(' ');
+                    speech.push_str(&word);
+                    speech.push(' ');
+                }
+            }
+            '^' => speech.push_str(" superscript "),
+            '_' => speech.push_str(" subscript "),
+            '{' | '}' | '$' | '&' | '~' => speech.push(' '),
+            c => speech.push(c),
+        }
+    }
+    speech.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn run(command: &[String], input: &str) -> Result<String> {
+    let (program, args) = command.split_first().context("empty command")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("running {}", program))?;
+    child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "{} failed on '{}': {}",
+            program,
+            input,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
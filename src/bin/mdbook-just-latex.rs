@@ -0,0 +1,25 @@
+use std::{
+    env,
+    io::{stdin, stdout, Read, Write},
+    process,
+};
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("supports") {
+        // The output is HTML, so only the HTML renderer is supported.
+        let supported = args.get(2).map(String::as_str) == Some("html");
+        process::exit(if supported { 0 } else { 1 });
+    }
+    let mut buffer = String::new();
+    let _ = stdin().read_to_string(&mut buffer)?;
+    let (context, mut book): (Value, Value) =
+        serde_json::from_str(&buffer).context("reading the book from mdBook")?;
+    just_latex::mdbook::preprocess(&context, &mut book)?;
+    let output = serde_json::to_vec(&book)?;
+    stdout().write_all(&output)?;
+    Ok(())
+}
@@ -0,0 +1,179 @@
+//! Persistent on-disk cache of rendered fragments.
+//!
+//! Rebuilding a site re-renders every post, although most of them have not changed since the last
+//! build. The cache remembers, for each fragment, where it ended up in which page SVG, so that a
+//! fragment seen before can skip LaTeX compilation entirely.
+//!
+//! The cache folder has the following layout:
+//!
+//! - `fragments/<key>.json`: one entry per fragment, see [`CachedFragment`]. The key is a SHA-256
+//!   of everything that may influence the rendering of the fragment: the preamble, the engine
+//!   settings, the macro definitions preceding it and its expanded template.
+//! - `pages/<hash>.svg`: page SVGs referenced by the entries above, named after the SHA-256 of
+//!   their content.
+//!
+//! Since page SVGs are content-addressed, stale pages are harmless and the whole folder can be
+//! deleted at any time.
+//!
+//! Several builds may share the cache at the same time, so files are written to a temporary file
+//! and renamed into place, and pages are checked against their hash when read. A page that does
+//! not match, e.g. left truncated by a crash, is a miss and gets written again.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+/// A rendered fragment, as stored in the cache.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedFragment {
+    pub images: Vec<CachedImage>,
+}
+
+/// One `<img>` of a rendered fragment (display fragments may span multiple pages).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedImage {
+    /// SHA-256 of the page SVG, in hex.
+    pub page: String,
+    /// The viewBox into the page SVG, in SVG coordinates.
+    pub view_box: (f64, f64, f64, f64),
+    /// Distance between the baseline and the bottom of the image. Only meaningful for inline
+    /// fragments.
+    pub depth: f64,
+}
+
+pub struct Cache {
+    fragments_dir: PathBuf,
+    pages_dir: PathBuf,
+}
+
+impl Cache {
+    /// Opens the cache at `dir`, creating it if necessary.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let fragments_dir = dir.join("fragments");
+        let pages_dir = dir.join("pages");
+        fs::create_dir_all(&fragments_dir)
+            .with_context(|| format!("creating cache folder {}", fragments_dir.display()))?;
+        fs::create_dir_all(&pages_dir)
+            .with_context(|| format!("creating cache folder {}", pages_dir.display()))?;
+        Ok(Self {
+            fragments_dir,
+            pages_dir,
+        })
+    }
+
+    /// Looks up a fragment, along with the data of the page of each of its images. Entries
+    /// referring to pages that are missing or do not match their hash are treated as misses.
+    pub fn get(&self, key: &str) -> Option<(CachedFragment, Vec<Vec<u8>>)> {
+        let data = fs::read(self.fragments_dir.join(format!("{}.json", key))).ok()?;
+        // A corrupted entry is just a miss, it will be overwritten later.
+        let entry: CachedFragment = serde_json::from_slice(&data).ok()?;
+        let pages = entry
+            .images
+            .iter()
+            .map(|image| self.get_page(&image.page))
+            .collect::<Option<_>>()?;
+        Some((entry, pages))
+    }
+
+    pub fn put(&self, key: &str, entry: &CachedFragment) -> Result<()> {
+        let path = self.fragments_dir.join(format!("{}.json", key));
+        write_atomically(&path, &serde_json::to_vec(entry)?)
+            .with_context(|| format!("writing cache entry {}", path.display()))
+    }
+
+    /// Reads a page, if it is there and intact.
+    fn get_page(&self, page: &str) -> Option<Vec<u8>> {
+        let data = fs::read(self.page_path(page)).ok()?;
+        (crate::hex(&Sha256::digest(&data)) == page).then_some(data)
+    }
+
+    pub fn put_page(&self, page: &str, data: &[u8]) -> Result<()> {
+        if self.get_page(page).is_some() {
+            // Content-addressed, so it must be the same page.
+            return Ok(());
+        }
+        let path = self.page_path(page);
+        write_atomically(&path, data)
+            .with_context(|| format!("writing cached page {}", path.display()))
+    }
+
+    fn page_path(&self, page: &str) -> PathBuf {
+        self.pages_dir.join(format!("{}.svg", page))
+    }
+}
+
+/// Writes a file so that readers see either its old content or all of the new one.
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let mut file = NamedTempFile::new_in(path.parent().context("cache file has no folder")?)?;
+    file.write_all(data)?;
+    file.persist(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn entry(page: &str) -> CachedFragment {
+        CachedFragment {
+            images: vec![CachedImage {
+                page: page.into(),
+                view_box: (0.0, 0.0, 10.0, 5.0),
+                depth: 1.0,
+            }],
+        }
+    }
+
+    fn page_name(data: &[u8]) -> String {
+        crate::hex(&Sha256::digest(data))
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new().unwrap();
+        let cache = Cache::open(dir.path()).unwrap();
+        let page = b"<svg/>";
+        cache.put_page(&page_name(page), page).unwrap();
+        cache.put("key", &entry(&page_name(page))).unwrap();
+        let (cached, pages) = cache.get("key").unwrap();
+        assert_eq!(cached.images[0].view_box, (0.0, 0.0, 10.0, 5.0));
+        assert_eq!(pages, [page.to_vec()]);
+        assert!(cache.get("other").is_none());
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(&cache.pages_dir).unwrap().count(), 1);
+        assert_eq!(fs::read_dir(&cache.fragments_dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn truncated_pages_are_misses_and_rewritten() {
+        let dir = TempDir::new().unwrap();
+        let cache = Cache::open(dir.path()).unwrap();
+        let page = b"<svg><path d=\"M0 0h1\"/></svg>";
+        let name = page_name(page);
+        fs::write(cache.page_path(&name), &page[..10]).unwrap();
+        cache.put("key", &entry(&name)).unwrap();
+        assert!(cache.get("key").is_none());
+        cache.put_page(&name, page).unwrap();
+        assert_eq!(cache.get("key").unwrap().1, [page.to_vec()]);
+    }
+
+    #[test]
+    fn missing_pages_and_corrupted_entries_are_misses() {
+        let dir = TempDir::new().unwrap();
+        let cache = Cache::open(dir.path()).unwrap();
+        cache.put("key", &entry(&page_name(b"<svg/>"))).unwrap();
+        assert!(cache.get("key").is_none());
+        fs::write(cache.fragments_dir.join("bad.json"), "{").unwrap();
+        assert!(cache.get("bad").is_none());
+    }
+}
@@ -1,23 +1,40 @@
-use std::{env, path::Path};
+use std::{
+    collections::HashSet,
+    env, fs,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{bail, format_err, Context, Result};
 use config::{builder::DefaultState, ConfigBuilder};
 use indoc::indoc;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Clone, Debug, Deserialize)]
+use crate::{engine::Engine, svg_utils};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub preamble: String,
     pub postamble: String,
-    /// Path to the latex executable.
-    pub latex: String,
+    /// The TeX engine, one of "pdflatex", "lualatex", "xelatex", "latex", "platex", "uplatex" or
+    /// "tectonic" (see [`crate::engine`] for the latter).
+    ///
+    /// It decides which command-line flags are passed to LaTeX and dvisvgm for the chosen mode.
+    /// If unset, it is taken from `latex` when that names an engine (e.g. `latex = "xelatex"`, as
+    /// older configs did), and is "pdflatex" otherwise.
+    pub engine: Engine,
+    /// Path to the latex executable. If none, the engine name is used.
+    pub latex: Option<String>,
     /// Path to the dvisvgm executable.
     pub dvisvgm: String,
-    /// Operating mode, either "pdf" or "dvi" or "xdv".
-    // Should have really made this an enum. But writing manual impls for Deserialize does not seem
-    // to worth the effort.
-    pub mode: String,
+    /// Operating mode, either "pdf" or "dvi" or "xdv". Not every engine supports every mode: "xdv"
+    /// is for xelatex only, and latex, platex and uplatex only support "dvi".
+    pub mode: Mode,
+    /// How fragments are located in the output, either "synctex" or "markers".
+    ///
+    /// With "synctex", boxes are looked up by line in the SyncTeX file. With "markers", each
+    /// fragment is surrounded by `\pdfsavepos` markers that record its exact position.
+    pub localization: Localization,
     /// Defines the error tolerance for [`crate::x_range_for_y_range`] and
     /// [`crate::refine_y_range`].dvi_
     pub y_range_tol: f64,
@@ -32,6 +49,29 @@ pub struct Config {
     ///
     /// A positive value makes inline fragments higher.
     pub baseline_rise: f64,
+    /// How page SVGs are delivered, one of "inline", "external", "data-uri", "data-uri-css" or
+    /// "svg".
+    ///
+    /// With "inline", they are embedded in the document as LZMA-compressed base64 blobs, along
+    /// with a script that decompresses them in the browser. With "external", they are written to
+    /// `asset_folder` and referenced by URL. With "data-uri", every <img> carries its page as a
+    /// base64 data URI, while with "data-uri-css" each page is defined once in a <style> as the
+    /// background image of a class, and fragments are <span>s showing the right part of it. The
+    /// last three need no script, so they also work in RSS readers and email clients.
+    ///
+    /// With "svg", the SVGs are inlined in the document as <svg> elements, which take the color of
+    /// the surrounding text. Pages, cropped or not (see `crop_fragments`), are defined once and
+    /// referenced with <use>.
+    pub output: Output,
+    /// Folder where SVGs are written in "external" output.
+    pub asset_folder: Option<String>,
+    /// Prefix of the URLs of the SVGs in "external" output, e.g. "/assets/jl/". The file name is
+    /// appended to it.
+    pub asset_url_prefix: String,
+    /// Whether to give each fragment a standalone SVG cropped to it, instead of showing its part of
+    /// the whole page SVG. This makes pages lighter for the browser and "save image" give just the
+    /// fragment, but similar glyphs can no longer be shared between fragments.
+    pub crop_fragments: bool,
     /// Path to lzma-d-min.js.
     pub lzma_js_path: String,
     /// Extra attributes to the decompressor <script> tag.
@@ -55,12 +95,161 @@ pub struct Config {
     pub template: TemplateConfig,
     /// Configuration for the SVG optimizer.
     pub optimizer: OptimizerConfig,
+    /// Color remapping, e.g. for dark themes.
+    pub colors: ColorConfig,
+    /// Text alternatives for screen readers.
+    pub accessibility: AccessibilityConfig,
     /// Output folder for intermediate files. Useful in case of LaTeX compilation errors.
     /// If none, the program dumps everything in a temp folder.
     pub output_folder: Option<String>,
+    /// Whether to keep the temp folder, when there is no `output_folder`. Its path is printed.
+    pub keep_temp: bool,
+    /// Folder for the persistent fragment cache. Fragments rendered in a previous run with the
+    /// same preamble, engine settings and macro definitions are taken from here instead of being
+    /// compiled again. If none, caching is disabled.
+    pub cache_folder: Option<String>,
+    /// Number of LaTeX jobs to run concurrently. Fragments are split into as many shards, each
+    /// compiled separately with all macro definitions replayed. Defaults to the number of CPUs.
+    pub jobs: Option<usize>,
+    /// What to do when LaTeX fails, either "strict" or "tolerant".
+    ///
+    /// In strict mode, any error aborts the whole conversion. In tolerant mode, the fragments
+    /// causing errors are left out of the compilation, and reported as warnings.
+    pub error_mode: ErrorMode,
+    /// How fragments left out in tolerant mode are rendered, either "math" or "code".
+    ///
+    /// With "math", they are left as math nodes for Pandoc to render. With "code", they are shown
+    /// as `<code class="jl-error">` elements, with the error message as title.
+    pub error_fallback: ErrorFallback,
+    /// The profile to use, from the `profiles` table. Profiles are partial configurations that
+    /// overlay config files, so that documents needing e.g. TikZ can pick a preamble with
+    /// `jlconfig.profile: tikz` in their front matter. They may inherit from another profile:
+    ///
+    /// ```toml
+    /// [profiles.tikz]
+    /// preamble = '''
+    /// \documentclass[12pt, fleqn]{article}
+    /// \usepackage[top=0cm, bottom=0cm, left=0cm, right=0cm, paperheight=16000pt]{geometry}
+    /// \usepackage{amsmath, amssymb, tikz}
+    /// \setlength{\parindent}{0pt}
+    /// \begin{document}'''
+    ///
+    /// [profiles.tikz-cjk]
+    /// inherits = "tikz"
+    /// engine = "xelatex"
+    /// mode = "xdv"
+    /// ```
+    pub profile: Option<String>,
+    /// Where each value was set, for error messages.
+    #[serde(skip)]
+    pub origins: Origins,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Pdf,
+    Dvi,
+    Xdv,
+}
+
+impl Mode {
+    pub const VARIANTS: [Self; 3] = [Mode::Pdf, Mode::Dvi, Mode::Xdv];
+
+    /// Name of the mode, also the extension of the output of LaTeX.
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Pdf => "pdf",
+            Mode::Dvi => "dvi",
+            Mode::Xdv => "xdv",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Localization {
+    Synctex,
+    Markers,
+}
+
+impl Localization {
+    pub const VARIANTS: [Self; 2] = [Localization::Synctex, Localization::Markers];
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Output {
+    Inline,
+    External,
+    DataUri,
+    DataUriCss,
+    Svg,
+}
+
+impl Output {
+    pub const VARIANTS: [Self; 5] = [
+        Output::Inline,
+        Output::External,
+        Output::DataUri,
+        Output::DataUriCss,
+        Output::Svg,
+    ];
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorMode {
+    Strict,
+    Tolerant,
+}
+
+impl ErrorMode {
+    pub const VARIANTS: [Self; 2] = [ErrorMode::Strict, ErrorMode::Tolerant];
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorFallback {
+    Math,
+    Code,
+}
+
+impl ErrorFallback {
+    pub const VARIANTS: [Self; 2] = [ErrorFallback::Math, ErrorFallback::Code];
+}
+
+/// Where config values were set, e.g. in which file or front matter key.
+///
+/// Like the config crate, this tells sources (files and profiles) from overrides (document
+/// metadata, environment and command line), which win regardless of the order they are added in.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Origins {
+    sources: Vec<(String, String)>,
+    overrides: Vec<(String, String)>,
+}
+
+impl Origins {
+    fn add_source(&mut self, key: &str, origin: impl Into<String>) {
+        self.sources.push((key.into(), origin.into()));
+    }
+
+    fn add_override(&mut self, key: &str, origin: impl Into<String>) {
+        self.overrides.push((key.into(), origin.into()));
+    }
+
+    /// Describes where `key`, or a value under it, was last set.
+    pub fn of(&self, key: &str) -> &str {
+        self.sources
+            .iter()
+            .chain(&self.overrides)
+            .rev()
+            .find(|(set, _)| overlap(set, key))
+            .map_or("the defaults", |(_, origin)| origin)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct TemplateConfig {
     /// The placeholder that will be replaced by the fragment content for all templates below.
     pub placeholder: String,
@@ -76,7 +265,7 @@ pub struct TemplateConfig {
     pub display_math: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct OptimizerConfig {
     /// Is the optimizer enabled?
     pub enabled: bool,
@@ -84,11 +273,295 @@ pub struct OptimizerConfig {
     pub eps: f64,
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ColorConfig {
+    /// Rules replacing colors in the SVGs. Colors not matched by any rule, such as those set with
+    /// xcolor, are left as they are.
+    pub rules: Vec<ColorRule>,
+}
+
+/// A color remapping rule, e.g.
+///
+/// ```toml
+/// [[colors.rules]]
+/// from = "black"
+/// to = "currentColor"
+/// dark = "#e0e0e0"
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ColorRule {
+    /// The color to replace, as "black", "white", "#rgb", "#rrggbb" or "rgb(r, g, b)".
+    pub from: String,
+    /// What to replace it with: any CSS color, including "currentColor" (only meaningful with "svg"
+    /// output) and `var(...)`.
+    pub to: Option<String>,
+    /// What to replace it with when the reader prefers a dark color scheme. This is done with a
+    /// `prefers-color-scheme` media query in the SVGs, so it works with all outputs.
+    pub dark: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccessibilityConfig {
+    /// What screen readers say for math fragments, one of "source" (the TeX source), "plain" (the
+    /// source without backslashes and braces) or "command" (the output of `speech_command`).
+    pub speech: Speech,
+    /// Command producing speech text, as a program followed by its arguments. It reads the TeX
+    /// source of a fragment on stdin.
+    pub speech_command: Vec<String>,
+    /// Command producing MathML, as a program followed by its arguments. It reads the TeX source
+    /// of a fragment on stdin. If set, math fragments come with visually hidden MathML, and their
+    /// images are hidden from screen readers.
+    pub mathml_command: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Speech {
+    Source,
+    Plain,
+    Command,
+}
+
+impl Speech {
+    pub const VARIANTS: [Self; 3] = [Speech::Source, Speech::Plain, Speech::Command];
+}
+
+/// Configuration given on the command line.
+///
+/// Sources are read in this order, later ones overriding earlier ones:
+///
+/// 1. the defaults;
+/// 2. `jlconfig.toml` in the directory of the executable;
+/// 3. `jlconfig.toml` in the XDG config directory, i.e. `$XDG_CONFIG_HOME/just-latex` or
+///    `~/.config/just-latex`;
+/// 4. every `jlconfig.toml` from the project root down to the directory of the input document
+///    (see [`Self::input_file`]), the project root being the closest ancestor with a `.git`;
+/// 5. the file named by `$JUST_LATEX_CONFIG`;
+/// 6. [`Self::config_file`], and then the selected profile (see [`Config::profile`]);
+/// 7. the document metadata;
+/// 8. `JUST_LATEX_` environment variables, e.g. `JUST_LATEX_OPTIMIZER__EPS` for `optimizer.eps`;
+/// 9. [`Self::values`].
+#[derive(Clone, Debug, Default)]
+pub struct Overrides {
+    /// The input document. If none, it is taken from `$PANDOC_INPUT_FILES`, and failing that, the
+    /// search for project config files starts in the working directory.
+    pub input_file: Option<PathBuf>,
+    /// An extra config file.
+    pub config_file: Option<PathBuf>,
+    /// Values overriding everything else, by key, e.g. `("mode", "dvi")`.
+    pub values: Vec<(String, String)>,
+}
+
+impl Overrides {
+    /// Finds the config files to read, in order.
+    pub fn config_files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        if let Some(dir) = env::current_exe()?.parent() {
+            files.push(dir.join("jlconfig.toml"));
+        }
+        let xdg_dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+            _ => env::var_os("HOME").map(|home| Path::new(&home).join(".config")),
+        };
+        if let Some(dir) = xdg_dir {
+            files.push(dir.join("just-latex").join("jlconfig.toml"));
+        }
+
+        let input_file = self.input_file.clone().or_else(pandoc_input_file);
+        let start = match input_file.as_ref().and_then(|file| file.parent()) {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.canonicalize()?,
+            _ => env::current_dir()?,
+        };
+        let mut project_files = vec![];
+        for dir in start.ancestors() {
+            project_files.push(dir.join("jlconfig.toml"));
+            if dir.join(".git").exists() {
+                break;
+            }
+        }
+        files.extend(project_files.into_iter().rev());
+
+        if let Some(file) = env::var_os("JUST_LATEX_CONFIG") {
+            let file = PathBuf::from(file);
+            if !file.exists() {
+                bail!(
+                    "JUST_LATEX_CONFIG names {}, which does not exist",
+                    file.display()
+                );
+            }
+            files.push(file);
+        }
+        files.retain(|file| file.is_file());
+        files.extend(self.config_file.clone());
+        // The same file may be found twice, e.g. when the executable is in the project.
+        let mut seen = HashSet::new();
+        files.retain(|file| seen.insert(file.canonicalize().unwrap_or_else(|_| file.clone())));
+        Ok(files)
+    }
+}
+
+/// Whether setting one key sets the other too, e.g. "template.header" and "template.header[0]".
+fn overlap(a: &str, b: &str) -> bool {
+    let nested = |outer: &str, inner: &str| {
+        inner
+            .strip_prefix(outer)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']))
+    };
+    nested(a, b) || nested(b, a)
+}
+
+/// Config values set in the environment, by key. Variables are named after keys in uppercase,
+/// prefixed with `JUST_LATEX_` and with `__` between levels, so `JUST_LATEX_OPTIMIZER__EPS` sets
+/// `optimizer.eps`. Numeric levels index arrays: `JUST_LATEX_TEMPLATE__HEADER__0` sets the first
+/// element of `template.header`.
+fn env_overrides() -> Vec<(String, String, String)> {
+    let mut values = vec![];
+    for (name, value) in env::vars() {
+        let stripped = match name.strip_prefix("JUST_LATEX_") {
+            // Not a config key, but where to find a config file.
+            Some("CONFIG") | None => continue,
+            Some(stripped) => stripped,
+        };
+        let mut key = String::new();
+        for level in stripped.split("__") {
+            if !key.is_empty() && level.bytes().all(|c| c.is_ascii_digit()) {
+                key.push_str(&format!("[{}]", level));
+            } else {
+                if !key.is_empty() {
+                    key.push('.');
+                }
+                key.push_str(&level.to_lowercase());
+            }
+        }
+        values.push((name, key, value));
+    }
+    // The environment is in no particular order.
+    values.sort();
+    values
+}
+
+/// The first input file passed to Pandoc, which it gives to filters as a JSON list.
+fn pandoc_input_file() -> Option<PathBuf> {
+    let files = env::var("PANDOC_INPUT_FILES").ok()?;
+    match serde_json::from_str::<Vec<String>>(&files) {
+        Ok(files) => files.into_iter().next().map(PathBuf::from),
+        Err(_) => Some(PathBuf::from(files)),
+    }
+}
+
+/// Allowed values of the keys holding enums. serde does not say which key holds an unknown
+/// variant, so they are checked beforehand.
+fn choices() -> [(&'static str, Vec<String>); 7] {
+    [
+        ("engine", names(&Engine::VARIANTS)),
+        ("mode", names(&Mode::VARIANTS)),
+        ("localization", names(&Localization::VARIANTS)),
+        ("output", names(&Output::VARIANTS)),
+        ("error_mode", names(&ErrorMode::VARIANTS)),
+        ("error_fallback", names(&ErrorFallback::VARIANTS)),
+        ("accessibility.speech", names(&Speech::VARIANTS)),
+    ]
+}
+
+/// The names serde gives to `variants`.
+fn names<T: Serialize>(variants: &[T]) -> Vec<String> {
+    variants
+        .iter()
+        .filter_map(|variant| match serde_json::to_value(variant) {
+            Ok(Value::String(name)) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
 impl Config {
     /// Loads configuration from config files, as well as document.
-    pub fn load(tree: &Value) -> Result<Self> {
+    pub fn load(tree: &Value, overrides: &Overrides) -> Result<Self> {
+        let mut origins = Origins::default();
+        let c = Self::builder(overrides, &mut origins)?;
+        let mut meta = vec![];
+        for (key, value) in tree["meta"]
+            .as_object()
+            .context("reading document metadata")?
+        {
+            if key == "jlconfig" {
+                if value["t"] != "MetaMap" {
+                    bail!("in Front Matter configuration, jlconfig must be a map!");
+                }
+                for (sub_key, value) in value["c"].as_object().context("reading map of MetaMap")? {
+                    walk_meta(&mut meta, value, sub_key, &mut origins)?;
+                }
+            } else if let Some(key) = key.strip_prefix("jlconfig.") {
+                walk_meta(&mut meta, value, key, &mut origins)?;
+            }
+        }
+        Self::build(c, meta, overrides, origins)
+    }
+
+    /// Loads configuration from config files only, for use without a Pandoc document.
+    pub fn load_files(overrides: &Overrides) -> Result<Self> {
+        let mut origins = Origins::default();
+        let c = Self::builder(overrides, &mut origins)?;
+        Self::build(c, vec![], overrides, origins)
+    }
+
+    /// Loads configuration from the `[preprocessor.just-latex]` table of an mdBook's `book.toml`,
+    /// as given to preprocessors. Config files are not read.
+    pub fn load_book(table: &Value) -> Result<Self> {
+        let mut origins = Origins::default();
+        let mut c = Self::defaults()?;
+        if !table.is_null() {
+            add_origins(
+                &mut origins,
+                "",
+                table,
+                "book.toml [preprocessor.just-latex]",
+            );
+            c = c.add_source(config::File::from_str(
+                &table.to_string(),
+                config::FileFormat::Json,
+            ));
+        }
+        Self::build(c, vec![], &Overrides::default(), origins)
+    }
+
+    /// Defaults, overridden by config files.
+    fn builder(
+        overrides: &Overrides,
+        origins: &mut Origins,
+    ) -> Result<ConfigBuilder<DefaultState>> {
+        let mut c = Self::defaults()?;
+        let files = overrides.config_files()?;
+        if !files.is_empty() {
+            eprintln!(
+                "Merging config files: {}",
+                files
+                    .iter()
+                    .map(|file| file.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        for file in files {
+            // Errors are left to the config crate, which reports them better.
+            if let Some(table) = fs::read_to_string(&file)
+                .ok()
+                .and_then(|content| toml::from_str::<toml::Value>(&content).ok())
+                .and_then(|table| serde_json::to_value(table).ok())
+            {
+                add_origins(origins, "", &table, &file.display().to_string());
+            }
+            c = c.add_source(config::File::new(
+                file.to_str().context("cannot convert path to string")?,
+                config::FileFormat::Toml,
+            ));
+        }
+        Ok(c)
+    }
+
+    fn defaults() -> Result<ConfigBuilder<DefaultState>> {
         let placeholder = "{{fragment}}";
-        let mut c = config::Config::builder()
+        let c = config::Config::builder()
             .set_default(
                 "preamble",
                 indoc! {r"
@@ -100,18 +573,29 @@ impl Config {
                 },
             )?
             .set_default("postamble", r"\end{document}")?
-            .set_default("latex", "pdflatex")?
+            .set_default("latex", Option::<String>::None)?
             .set_default("dvisvgm", "dvisvgm")?
             .set_default("mode", "pdf")?
+            .set_default("localization", "synctex")?
             .set_default("y_range_tol", 0.0)?
             .set_default("x_range_margin", 1.0)?
             .set_default("y_range_margin", 1.0)?
             .set_default("baseline_rise", 0.0)?
+            .set_default("output", "inline")?
+            .set_default("asset_folder", Option::<String>::None)?
+            .set_default("asset_url_prefix", "")?
+            .set_default("crop_fragments", false)?
             .set_default("lzma_js_path", "https://cdn.jsdelivr.net/npm/lzma@2/src/lzma-d-min.js")?
             .set_default("script_extra_attributes", "")?
             .set_default("extra_style_inline", "")?
             .set_default("extra_style_display", "")?
             .set_default("output_folder", Option::<String>::None)?
+            .set_default("keep_temp", false)?
+            .set_default("profile", Option::<String>::None)?
+            .set_default("cache_folder", Option::<String>::None)?
+            .set_default("jobs", Option::<i64>::None)?
+            .set_default("error_mode", "strict")?
+            .set_default("error_fallback", "math")?
             // Default templates...
             .set_default("template.placeholder", placeholder)?
             .set_default("template.inline_math", format!(r"\begin{{displaymath}}{}\end{{displaymath}}", placeholder))?
@@ -127,75 +611,300 @@ impl Config {
             )?
             .set_default("template.display_math", format!(r"\begin{{displaymath}}{}\end{{displaymath}}", placeholder))?
             .set_default("optimizer.enabled", false)?
-            .set_default("optimizer.eps", 0.001)?;
+            .set_default("optimizer.eps", 0.001)?
+            .set_default("colors.rules", Vec::<String>::new())?
+            .set_default("accessibility.speech", "source")?
+            .set_default("accessibility.speech_command", Vec::<String>::new())?
+            .set_default("accessibility.mathml_command", Vec::<String>::new())?;
+        Ok(c)
+    }
 
-        let exe_config = env::current_exe()?.join("jlconfig.toml");
-        if exe_config.exists() {
-            c = c.add_source(config::File::new(
-                exe_config
-                    .to_str()
-                    .context("cannot convert path to string")?,
-                config::FileFormat::Toml,
-            ));
+    /// Applies the overrides, in increasing order of precedence: the document metadata `meta`,
+    /// the environment and `overrides`.
+    fn build(
+        mut c: ConfigBuilder<DefaultState>,
+        mut meta: Vec<(String, config::Value)>,
+        overrides: &Overrides,
+        mut origins: Origins,
+    ) -> Result<Self> {
+        for (name, key, value) in env_overrides() {
+            origins.add_override(&key, format!("environment variable {}", name));
+            meta.push((key, value.into()));
         }
-
-        if Path::new("jlconfig.toml").exists() {
-            c = c.add_source(config::File::new("jlconfig.toml", config::FileFormat::Toml));
+        for (key, value) in &overrides.values {
+            origins.add_override(key, "the command line");
+            meta.push((key.clone(), value.as_str().into()));
         }
-
-        for (key, value) in tree["meta"]
-            .as_object()
-            .context("reading document metadata")?
-        {
-            if key == "jlconfig" {
-                if value["t"] != "MetaMap" {
-                    bail!("in Front Matter configuration, jlconfig must be a map!");
-                }
-                for (sub_key, value) in value["c"].as_object().context("reading map of MetaMap")? {
-                    c = walk_meta(c, value, sub_key)?;
+        // The config crate keeps overrides in a hash map, so which of two overlapping keys (e.g.
+        // "template.header" and "template.header[0]") wins would be left to chance. Only the last
+        // of them is applied instead.
+        for (i, (key, value)) in meta.iter().enumerate() {
+            if !meta[i + 1..].iter().any(|(later, _)| overlap(key, later)) {
+                c = c.set_override(key.as_str(), value.clone())?;
+            }
+        }
+        let c = add_profile(c, &mut origins)?;
+        let c = infer_engine(c, &mut origins)?.build()?;
+        for (key, choices) in choices() {
+            if let Ok(value) = c.get_string(key) {
+                if !choices.contains(&value) {
+                    bail!(
+                        "unknown {} '{}' (set by {}): must be one of '{}'",
+                        key,
+                        value,
+                        origins.of(key),
+                        choices.join("', '")
+                    );
                 }
-            } else if let Some(key) = key.strip_prefix("jlconfig.") {
-                c = walk_meta(c, value, key)?;
             }
         }
-
-        c.build()?
+        let mut config: Self = c
             .try_deserialize()
-            .map_err(|e| format_err!("cannot load config: {}", e))
+            .map_err(|e| format_err!("cannot load config: {}", e))?;
+        config.origins = origins;
+        Ok(config)
+    }
+
+    /// The latex executable to run.
+    pub fn latex_executable(&self) -> &str {
+        self.latex.as_deref().unwrap_or_else(|| self.engine.name())
+    }
+
+    /// A key with where it was set, for error messages.
+    fn set_by(&self, key: &str) -> String {
+        format!("{} (set by {})", key, self.origins.of(key))
     }
 
     pub fn sanity_check(&self) -> Result<()> {
-        if self.mode != "pdf" && self.mode != "dvi" && self.mode != "xdv" {
-            bail!("unknown mode: must be one of 'pdf', 'dvi', or 'xdv'");
+        if !self.engine.supported_modes().contains(&self.mode) {
+            bail!(
+                "engine '{}' does not support mode '{}' (supported: {}), set engine to one that \
+                 does or change the mode, see {} and {}",
+                self.engine.name(),
+                self.mode.name(),
+                self.engine
+                    .supported_modes()
+                    .iter()
+                    .map(|mode| mode.name())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                self.set_by("engine"),
+                self.set_by("mode")
+            );
+        }
+        if self.y_range_tol < 0.0 {
+            bail!("{} must not be negative", self.set_by("y_range_tol"));
+        }
+        if self.optimizer.eps <= 0.0 {
+            bail!("{} must be positive", self.set_by("optimizer.eps"));
+        }
+        if self.output == Output::External && self.asset_folder.is_none() {
+            bail!(
+                "external output needs an asset_folder, see {}",
+                self.set_by("output")
+            );
         }
-        if self.mode != "pdf" && self.optimizer.enabled {
-            bail!("DVI/XDV mode is incompatible with JustLaTeX's SVG optimizer");
+        if self.jobs == Some(0) {
+            bail!("{} must be at least 1", self.set_by("jobs"));
+        }
+        if self.mode != Mode::Pdf && self.optimizer.enabled {
+            bail!(
+                "DVI/XDV mode is incompatible with JustLaTeX's SVG optimizer, see {} and {}",
+                self.set_by("mode"),
+                self.set_by("optimizer.enabled")
+            );
+        }
+        let template = &self.template;
+        if template.header.len() != 6 {
+            bail!(
+                "{} must have exactly 6 entries, one per header level, but has {}",
+                self.set_by("template.header"),
+                template.header.len()
+            );
+        }
+        let templates = [
+            ("inline_math", &template.inline_math),
+            ("inline_math_inner", &template.inline_math_inner),
+            ("strong", &template.strong),
+            ("emph", &template.emph),
+            ("quote", &template.quote),
+            ("display_math", &template.display_math),
+        ]
+        .into_iter()
+        .map(|(key, template)| (format!("template.{}", key), template))
+        .chain(
+            template
+                .header
+                .iter()
+                .enumerate()
+                .map(|(i, template)| (format!("template.header[{}]", i), template)),
+        );
+        for (key, value) in templates {
+            if !value.contains(&template.placeholder) {
+                bail!(
+                    "{} does not contain the placeholder '{}', see {}",
+                    self.set_by(&key),
+                    template.placeholder,
+                    self.set_by("template.placeholder")
+                );
+            }
+        }
+        if self.accessibility.speech == Speech::Command
+            && self.accessibility.speech_command.is_empty()
+        {
+            bail!(
+                "{} is 'command' but no speech_command is set",
+                self.set_by("accessibility.speech")
+            );
+        }
+        for (i, rule) in self.colors.rules.iter().enumerate() {
+            let key = format!("colors.rules[{}]", i);
+            if svg_utils::normalize_color(&rule.from).is_none() {
+                bail!("unsupported color '{}' in {}", rule.from, self.set_by(&key));
+            }
+            if rule.to.is_none() && rule.dark.is_none() {
+                bail!(
+                    "color rule for '{}' needs a 'to' or a 'dark' color, see {}",
+                    rule.from,
+                    self.set_by(&key)
+                );
+            }
         }
         Ok(())
     }
 }
 
+/// Sets the engine when it is not set, after the name of the latex executable if it is the name of
+/// an engine, or to pdflatex.
+fn infer_engine(
+    c: ConfigBuilder<DefaultState>,
+    origins: &mut Origins,
+) -> Result<ConfigBuilder<DefaultState>> {
+    let built = c.build_cloned()?;
+    if built.get_string("engine").is_ok() {
+        return Ok(c);
+    }
+    let inferred = built.get_string("latex").ok().and_then(|latex| {
+        let name = Path::new(&latex).file_stem()?.to_str()?.to_lowercase();
+        let engine: Engine = serde_json::from_value(Value::String(name)).ok()?;
+        Some(engine)
+    });
+    Ok(match inferred {
+        Some(engine) => {
+            origins.add_source("engine", format!("latex, in {}", origins.of("latex")));
+            c.set_default("engine", engine.name())?
+        }
+        None => c.set_default("engine", Engine::PdfLaTeX.name())?,
+    })
+}
+
+/// Overlays the selected profile, and those it inherits from, on the config files. Profiles are
+/// sources, so the document metadata and other overrides still win over them.
+fn add_profile(
+    mut c: ConfigBuilder<DefaultState>,
+    origins: &mut Origins,
+) -> Result<ConfigBuilder<DefaultState>> {
+    let built = c.build_cloned()?;
+    let mut name = match built.get_string("profile") {
+        Ok(name) => name,
+        Err(_) => return Ok(c),
+    };
+    let profiles: Value = built.get("profiles").unwrap_or_default();
+    // Where the name of the current profile comes from.
+    let mut key = "profile".to_string();
+    // The selected profile first, then the ones it inherits from.
+    let mut chain: Vec<(String, Value)> = vec![];
+    loop {
+        if chain.iter().any(|(seen, _)| *seen == name) {
+            let cycle = chain
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .chain([name.as_str()])
+                .collect::<Vec<_>>();
+            bail!(
+                "profile '{}' inherits from itself: {} (set by {})",
+                name,
+                cycle.join(" -> "),
+                origins.of(&key)
+            );
+        }
+        let mut profile = match profiles.get(&name) {
+            Some(profile @ Value::Object(_)) => profile.clone(),
+            _ => bail!(
+                "unknown profile '{}' in {} (set by {})",
+                name,
+                key,
+                origins.of(&key)
+            ),
+        };
+        let parent = profile.as_object_mut().unwrap().remove("inherits");
+        key = format!("profiles.{}.inherits", name);
+        chain.push((name, profile));
+        name = match parent {
+            Some(Value::String(parent)) => parent,
+            Some(_) => bail!("{} must be the name of a profile", key),
+            None => break,
+        };
+    }
+    for (name, profile) in chain.into_iter().rev() {
+        add_origins(origins, "", &profile, &format!("profile {}", name));
+        c = c.add_source(config::File::from_str(
+            &profile.to_string(),
+            config::FileFormat::Json,
+        ));
+    }
+    Ok(c)
+}
+
+/// Records that every value in `value` was set by `origin`.
+fn add_origins(origins: &mut Origins, key: &str, value: &Value, origin: &str) {
+    match value {
+        Value::Object(map) => {
+            for (sub_key, value) in map {
+                let sub_key = if key.is_empty() {
+                    sub_key.clone()
+                } else {
+                    format!("{}.{}", key, sub_key)
+                };
+                add_origins(origins, &sub_key, value, origin);
+            }
+        }
+        Value::Array(values) => {
+            for (i, value) in values.iter().enumerate() {
+                add_origins(origins, &format!("{}[{}]", key, i), value, origin);
+            }
+        }
+        _ => origins.add_source(key, origin),
+    }
+}
+
+/// Collects the config values set by a front matter value at `key` into `values`.
 fn walk_meta(
-    mut cb: ConfigBuilder<DefaultState>,
+    values: &mut Vec<(String, config::Value)>,
     value: &Value,
     key: &str,
-) -> Result<ConfigBuilder<DefaultState>> {
-    match value["t"].as_str().context("reading type of Meta")? {
+    origins: &mut Origins,
+) -> Result<()> {
+    let ty = value["t"].as_str().context("reading type of Meta")?;
+    if ty != "MetaMap" && ty != "MetaList" {
+        origins.add_override(key, format!("front matter key jlconfig.{}", key));
+    }
+    let value: config::Value = match ty {
         "MetaInlines" => {
             let mut content = String::new();
             meta_inlines_to_string(&value["c"], &mut content)?;
-            Ok(cb.set_override(key, content)?)
+            content.into()
         }
         "MetaBlocks" => {
             let mut content = String::new();
             meta_blocks_to_string(&value["c"], &mut content)?;
-            Ok(cb.set_override(key, content)?)
+            content.into()
         }
         "MetaMap" => {
             for (sub_key, value) in value["c"].as_object().context("reading map of MetaMap")? {
-                cb = walk_meta(cb, value, &format!("{}.{}", key, sub_key))?;
+                walk_meta(values, value, &format!("{}.{}", key, sub_key), origins)?;
             }
-            Ok(cb)
+            return Ok(());
         }
         "MetaList" => {
             for (i, value) in value["c"]
@@ -204,20 +913,22 @@ fn walk_meta(
                 .iter()
                 .enumerate()
             {
-                cb = walk_meta(cb, value, &format!("{}[{}]", key, i))?;
+                walk_meta(values, value, &format!("{}[{}]", key, i), origins)?;
             }
-            Ok(cb)
-        }
-        "MetaString" => Ok(cb.set_override(
-            key,
-            value["c"].as_str().context("reading value of MetaString")?,
-        )?),
-        "MetaBool" => Ok(cb.set_override(
-            key,
-            value["c"].as_bool().context("reading value of MetaBool")?,
-        )?),
+            return Ok(());
+        }
+        "MetaString" => value["c"]
+            .as_str()
+            .context("reading value of MetaString")?
+            .into(),
+        "MetaBool" => value["c"]
+            .as_bool()
+            .context("reading value of MetaBool")?
+            .into(),
         ty => bail!("unsupported Meta type: {}", ty),
-    }
+    };
+    values.push((key.to_string(), value));
+    Ok(())
 }
 
 fn meta_inlines_to_string(value: &Value, content: &mut String) -> Result<()> {
@@ -256,3 +967,379 @@ fn meta_blocks_to_string(value: &Value, content: &mut String) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Tests reading the environment must not run concurrently.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Runs `f` with `vars` as the only `JUST_LATEX_` variables and `XDG_CONFIG_HOME`. Tests
+    /// reading config from the environment go through it, so as not to see those of other tests.
+    pub(crate) fn with_env<T>(vars: &[(&str, &Path)], f: impl FnOnce() -> T) -> T {
+        let _lock = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let saved: Vec<_> = env::vars_os()
+            .filter(|(name, _)| {
+                let name = name.to_string_lossy();
+                name.starts_with("JUST_LATEX_") || name == "XDG_CONFIG_HOME"
+            })
+            .collect();
+        for (name, _) in &saved {
+            env::remove_var(name);
+        }
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+        let ret = f();
+        for (name, _) in vars {
+            env::remove_var(name);
+        }
+        for (name, value) in saved {
+            env::set_var(name, value);
+        }
+        ret
+    }
+
+    /// Runs `f` in a project with `config` as its jlconfig.toml and with `vars` as the only
+    /// `JUST_LATEX_` variables, so that no config from outside the project is read.
+    fn in_project<T>(config: &str, vars: &[(&str, &str)], f: impl FnOnce(Overrides) -> T) -> T {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join(".git")).unwrap();
+        fs::write(dir.path().join("jlconfig.toml"), config).unwrap();
+        let vars = vars
+            .iter()
+            .map(|&(name, value)| (name, Path::new(value)))
+            .chain([("XDG_CONFIG_HOME", dir.path())])
+            .collect::<Vec<_>>();
+        with_env(&vars, || {
+            f(Overrides {
+                input_file: Some(dir.path().join("doc.md")),
+                ..Overrides::default()
+            })
+        })
+    }
+
+    /// A document whose metadata has the given `jlconfig.` keys.
+    fn document(meta: Value) -> Value {
+        let meta = meta
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(key, value)| (format!("jlconfig.{}", key), value.clone()))
+            .collect::<serde_json::Map<_, _>>();
+        json!({ "pandoc-api-version": [1, 23], "meta": meta, "blocks": [] })
+    }
+
+    fn meta_string(value: &str) -> Value {
+        json!({ "t": "MetaString", "c": value })
+    }
+
+    #[test]
+    fn environment_sets_nested_keys() {
+        let vars = [
+            ("JUST_LATEX_OPTIMIZER__EPS", "0.5"),
+            ("JUST_LATEX_TEMPLATE__HEADER__0", r"\Huge{{fragment}}"),
+        ];
+        let config = in_project("", &vars, |overrides| Config::load_files(&overrides)).unwrap();
+        assert_eq!(config.optimizer.eps, 0.5);
+        assert_eq!(config.template.header[0], r"\Huge{{fragment}}");
+        assert_eq!(config.template.header.len(), 6);
+        assert!(config.template.header[1].contains("18pt"));
+        assert_eq!(
+            config.origins.of("optimizer.eps"),
+            "environment variable JUST_LATEX_OPTIMIZER__EPS"
+        );
+        assert_eq!(
+            config.origins.of("template.header[0]"),
+            "environment variable JUST_LATEX_TEMPLATE__HEADER__0"
+        );
+        assert_eq!(config.origins.of("template.header[1]"), "the defaults");
+    }
+
+    #[test]
+    fn files_front_matter_environment_and_command_line() {
+        let file = indoc! {"
+            x_range_margin = 2.0
+            y_range_margin = 2.0
+            baseline_rise = 2.0
+            [optimizer]
+            eps = 0.2
+        "};
+        let tree = document(json!({
+            "y_range_margin": meta_string("3"),
+            "baseline_rise": meta_string("3"),
+            "optimizer": { "t": "MetaMap", "c": { "eps": meta_string("0.3") } },
+        }));
+        let vars = [
+            ("JUST_LATEX_BASELINE_RISE", "4"),
+            ("JUST_LATEX_OPTIMIZER__EPS", "0.4"),
+        ];
+        let config = in_project(file, &vars, |mut overrides| {
+            overrides
+                .values
+                .push(("optimizer.eps".into(), "0.5".into()));
+            Config::load(&tree, &overrides)
+        })
+        .unwrap();
+        assert_eq!(config.x_range_margin, 2.0);
+        assert_eq!(config.y_range_margin, 3.0);
+        assert_eq!(config.baseline_rise, 4.0);
+        assert_eq!(config.optimizer.eps, 0.5);
+        assert!(config
+            .origins
+            .of("x_range_margin")
+            .ends_with("jlconfig.toml"));
+        assert_eq!(
+            config.origins.of("y_range_margin"),
+            "front matter key jlconfig.y_range_margin"
+        );
+        assert_eq!(
+            config.origins.of("baseline_rise"),
+            "environment variable JUST_LATEX_BASELINE_RISE"
+        );
+        assert_eq!(config.origins.of("optimizer.eps"), "the command line");
+    }
+
+    #[test]
+    fn environment_overrides_front_matter_lists() {
+        let headers: Vec<_> = (1..=6)
+            .map(|level| meta_string(&format!("h{}{{{{fragment}}}}", level)))
+            .collect();
+        let tree = document(json!({
+            "template": { "t": "MetaMap", "c": {
+                "header": { "t": "MetaList", "c": headers },
+            } },
+        }));
+        let vars = [("JUST_LATEX_TEMPLATE__HEADER__0", "env{{fragment}}")];
+        let config = in_project("", &vars, |overrides| Config::load(&tree, &overrides)).unwrap();
+        assert_eq!(config.template.header[0], "env{{fragment}}");
+        assert_eq!(config.template.header[1], "h2{{fragment}}");
+        assert_eq!(config.template.header.len(), 6);
+    }
+
+    #[test]
+    fn unknown_variants_name_the_key_and_choices() {
+        let err = in_project("output = \"png\"\n", &[], |overrides| {
+            Config::load_files(&overrides)
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(err.starts_with("unknown output 'png' (set by "), "{}", err);
+        assert!(
+            err.ends_with(
+                "jlconfig.toml): must be one of 'inline', 'external', 'data-uri', 'data-uri-css', \
+                 'svg'"
+            ),
+            "{}",
+            err
+        );
+        let choices = choices();
+        assert_eq!(
+            choices[0].1.join(" "),
+            "pdflatex lualatex xelatex latex platex uplatex tectonic"
+        );
+        assert_eq!(choices[6].1, ["source", "plain", "command"]);
+    }
+
+    #[test]
+    fn later_overrides_replace_overlapping_keys() {
+        let tree = document(json!({
+            "accessibility": { "t": "MetaMap", "c": {
+                "speech_command": { "t": "MetaList", "c": [meta_string("a"), meta_string("b")] },
+            } },
+        }));
+        for _ in 0..10 {
+            let config = in_project("", &[], |mut overrides| {
+                overrides
+                    .values
+                    .push(("accessibility.speech_command".into(), "c".into()));
+                Config::load(&tree, &overrides)
+            });
+            // Only the command line value is applied, whatever order the config crate applies
+            // overrides in, so the list becomes a string.
+            let err = config.unwrap_err().to_string();
+            assert!(err.contains(r#"invalid type: string "c""#), "{}", err);
+        }
+    }
+
+    /// Creates the files at `paths`, relative to `dir`, with their folders.
+    fn create(dir: &Path, paths: &[&str]) -> Vec<PathBuf> {
+        paths
+            .iter()
+            .map(|path| {
+                let path = dir.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, "").unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn config_files_in_order() {
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(dir.join("project/.git")).unwrap();
+        let files = create(
+            &dir,
+            &[
+                "xdg/just-latex/jlconfig.toml",
+                "project/jlconfig.toml",
+                "project/a/jlconfig.toml",
+                "project/a/b/c/jlconfig.toml",
+                "env.toml",
+                "extra.toml",
+            ],
+        );
+        // Not in the project, nor on the way down to the input.
+        create(&dir, &["jlconfig.toml", "project/a/d/jlconfig.toml"]);
+        let overrides = Overrides {
+            input_file: Some(dir.join("project/a/b/c/doc.md")),
+            config_file: Some(dir.join("extra.toml")),
+            values: vec![],
+        };
+        let vars = [
+            ("XDG_CONFIG_HOME", &*dir.join("xdg")),
+            ("JUST_LATEX_CONFIG", &*dir.join("env.toml")),
+        ];
+        let found = with_env(&vars, || overrides.config_files()).unwrap();
+        assert_eq!(found, files);
+    }
+
+    #[test]
+    fn config_files_up_to_the_root_without_git() {
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        let files = create(&dir, &["jlconfig.toml", "a/jlconfig.toml"]);
+        fs::create_dir(dir.join("a/b")).unwrap();
+        let overrides = Overrides {
+            input_file: Some(dir.join("a/b/doc.md")),
+            ..Overrides::default()
+        };
+        let vars = [("XDG_CONFIG_HOME", &*dir.join("xdg"))];
+        let found = with_env(&vars, || overrides.config_files()).unwrap();
+        // Config files of the folders above, up to the root, are read too.
+        let outside = dir
+            .ancestors()
+            .skip(1)
+            .map(|dir| dir.join("jlconfig.toml"))
+            .filter(|file| file.is_file())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            outside.into_iter().rev().chain(files).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn missing_config_from_the_environment() {
+        let dir = TempDir::new().unwrap();
+        let missing = dir.path().join("missing.toml");
+        let vars = [
+            ("XDG_CONFIG_HOME", dir.path()),
+            ("JUST_LATEX_CONFIG", &*missing),
+        ];
+        let error = with_env(&vars, || Overrides::default().config_files()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "JUST_LATEX_CONFIG names {}, which does not exist",
+                missing.display()
+            )
+        );
+    }
+
+    const PROFILES: &str = indoc! {r#"
+        x_range_margin = 1.5
+        y_range_margin = 1.5
+        baseline_rise = 1.5
+        [profiles.parent]
+        x_range_margin = 2.0
+        y_range_margin = 2.0
+        baseline_rise = 2.0
+        mode = "dvi"
+        [profiles.child]
+        inherits = "parent"
+        y_range_margin = 3.0
+        baseline_rise = 3.0
+    "#};
+
+    #[test]
+    fn child_profiles_override_their_parents() {
+        let config = in_project(PROFILES, &[("JUST_LATEX_PROFILE", "child")], |overrides| {
+            Config::load_files(&overrides)
+        })
+        .unwrap();
+        assert_eq!(config.x_range_margin, 2.0);
+        assert_eq!(config.y_range_margin, 3.0);
+        assert_eq!(config.mode, Mode::Dvi);
+        assert_eq!(config.origins.of("x_range_margin"), "profile parent");
+        assert_eq!(config.origins.of("y_range_margin"), "profile child");
+    }
+
+    #[test]
+    fn metadata_and_environment_override_profiles() {
+        let tree = document(json!({
+            "profile": meta_string("child"),
+            "baseline_rise": meta_string("4"),
+        }));
+        let vars = [("JUST_LATEX_Y_RANGE_MARGIN", "5")];
+        let config =
+            in_project(PROFILES, &vars, |overrides| Config::load(&tree, &overrides)).unwrap();
+        assert_eq!(config.x_range_margin, 2.0);
+        assert_eq!(config.y_range_margin, 5.0);
+        assert_eq!(config.baseline_rise, 4.0);
+        assert_eq!(config.profile.as_deref(), Some("child"));
+    }
+
+    #[test]
+    fn profiles_are_only_applied_when_selected() {
+        let config = in_project(PROFILES, &[], |overrides| Config::load_files(&overrides)).unwrap();
+        assert_eq!(config.x_range_margin, 1.5);
+        assert_eq!(config.mode, Mode::Pdf);
+    }
+
+    #[test]
+    fn inheritance_cycles() {
+        let file = indoc! {r#"
+            profile = "a"
+            [profiles.a]
+            inherits = "b"
+            [profiles.b]
+            inherits = "a"
+        "#};
+        let error = in_project(file, &[], |overrides| Config::load_files(&overrides))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("profile 'a' inherits from itself: a -> b -> a (set by "),
+            "{}",
+            error
+        );
+        assert!(error.ends_with("jlconfig.toml)"), "{}", error);
+    }
+
+    #[test]
+    fn unknown_profiles() {
+        let file = indoc! {r#"
+            [profiles.a]
+            inherits = "missing"
+        "#};
+        let error = in_project(file, &[("JUST_LATEX_PROFILE", "a")], |overrides| {
+            Config::load_files(&overrides)
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(
+            error.starts_with("unknown profile 'missing' in profiles.a.inherits (set by "),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! TeX engines and the command-line flags they need.
+//!
+//! Each engine supports a subset of the operating modes (see [`crate::config::Config::mode`]), and
+//! spells the flags selecting the output format a bit differently.
+//!
+//! Besides the engines of a TeX distribution, [Tectonic](https://tectonic-typesetting.github.io)
+//! can be used on machines without one. It is run as
+//! `tectonic --outfmt=<mode> --synctex --keep-logs --keep-intermediates --only-cached source.tex`:
+//! `--synctex`, `--keep-logs` and `--keep-intermediates` keep the SyncTeX, log and marker files
+//! that would otherwise be discarded, and `--only-cached` makes it work offline from its bundle
+//! cache, which therefore has to be populated beforehand (e.g. by compiling any document once with
+//! network access). Note that dvisvgm is still needed, and that in "xdv" mode it has to find the
+//! fonts used by the document, which Tectonic keeps in its cache; "pdf" mode has no such problem.
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Mode;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    PdfLaTeX,
+    LuaLaTeX,
+    XeLaTeX,
+    /// pdfTeX in DVI mode.
+    LaTeX,
+    /// Japanese pTeX, DVI only.
+    PLaTeX,
+    /// Unicode-aware pTeX, DVI only. Use it for CJK.
+    UpLaTeX,
+    /// The XeTeX-based Tectonic, as a self-contained alternative to a TeX distribution.
+    Tectonic,
+}
+
+impl Engine {
+    pub const VARIANTS: [Self; 7] = [
+        Engine::PdfLaTeX,
+        Engine::LuaLaTeX,
+        Engine::XeLaTeX,
+        Engine::LaTeX,
+        Engine::PLaTeX,
+        Engine::UpLaTeX,
+        Engine::Tectonic,
+    ];
+
+    /// Name of the engine, also the default executable.
+    pub fn name(self) -> &'static str {
+        match self {
+            Engine::PdfLaTeX => "pdflatex",
+            Engine::LuaLaTeX => "lualatex",
+            Engine::XeLaTeX => "xelatex",
+            Engine::LaTeX => "latex",
+            Engine::PLaTeX => "platex",
+            Engine::UpLaTeX => "uplatex",
+            Engine::Tectonic => "tectonic",
+        }
+    }
+
+    pub fn supported_modes(self) -> &'static [Mode] {
+        match self {
+            Engine::PdfLaTeX | Engine::LuaLaTeX => &[Mode::Pdf, Mode::Dvi],
+            Engine::XeLaTeX | Engine::Tectonic => &[Mode::Pdf, Mode::Xdv],
+            Engine::LaTeX | Engine::PLaTeX | Engine::UpLaTeX => &[Mode::Dvi],
+        }
+    }
+
+    /// Flags to compile in `mode`, with SyncTeX enabled and without stopping on errors. The source
+    /// file comes after them.
+    pub fn latex_args(self, mode: Mode) -> &'static [&'static str] {
+        match (self, mode) {
+            (Engine::PdfLaTeX, Mode::Dvi) => &[
+                "-output-format=dvi",
+                "-synctex=-1",
+                "-interaction=nonstopmode",
+            ],
+            (Engine::LuaLaTeX, Mode::Dvi) => &[
+                "--output-format=dvi",
+                "-synctex=-1",
+                "-interaction=nonstopmode",
+            ],
+            (Engine::XeLaTeX, Mode::Xdv) => &["-no-pdf", "-synctex=-1", "-interaction=nonstopmode"],
+            (Engine::Tectonic, Mode::Xdv) => &[
+                "--outfmt=xdv",
+                "--synctex",
+                "--keep-logs",
+                "--keep-intermediates",
+                "--only-cached",
+            ],
+            (Engine::Tectonic, _) => &[
+                "--outfmt=pdf",
+                "--synctex",
+                "--keep-logs",
+                "--keep-intermediates",
+                "--only-cached",
+            ],
+            _ => &["-synctex=-1", "-interaction=nonstopmode"],
+        }
+    }
+
+    /// Flags telling dvisvgm how to read the output of the engine in `mode`.
+    pub fn dvisvgm_args(self, mode: Mode) -> &'static [&'static str] {
+        match mode {
+            Mode::Pdf => &["--pdf"],
+            // Embedded fonts are patched and loaded by the SVG parser, which only understands TTF.
+            _ => &["--font-format=ttf"],
+        }
+    }
+}
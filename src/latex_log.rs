@@ -0,0 +1,97 @@
+//! Parsing of LaTeX log files.
+//!
+//! TeX reports errors in the log in a rather fixed format:
+//!
+//! ```text
+//! ! Undefined control sequence.
+//! l.12 $\foo
+//!           $
+//! ```
+//!
+//! That is, a line starting with `! ` holding the message, followed (possibly after some help
+//! text) by a line starting with `l.<n>` telling where in the source file the error was detected.
+
+use regex::Regex;
+
+/// An error found in the log.
+#[derive(Clone, Debug)]
+pub struct LogError {
+    /// The error message, without the leading `! `.
+    pub message: String,
+    /// The line in the source file where TeX detected the error, if reported.
+    pub line: Option<usize>,
+    /// What TeX had read on that line when the error occurred.
+    pub context: String,
+}
+
+/// Finds all errors in a LaTeX log.
+pub fn parse_errors(log: &str) -> Vec<LogError> {
+    let line_regex = Regex::new(r"^l\.(\d+) ?(.*)$").unwrap();
+    let mut errors: Vec<LogError> = vec![];
+    // Whether the last error is still waiting for its l.<n> line.
+    let mut pending = false;
+    for log_line in log.lines() {
+        if let Some(message) = log_line.strip_prefix("! ") {
+            errors.push(LogError {
+                message: message.trim().into(),
+                line: None,
+                context: String::new(),
+            });
+            pending = true;
+        } else if pending {
+            if let Some(captures) = line_regex.captures(log_line) {
+                let last = errors.last_mut().unwrap();
+                last.line = captures[1].parse().ok();
+                last.context = captures[2].trim().into();
+                pending = false;
+            }
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    #[test]
+    fn errors_with_and_without_lines() {
+        let log = fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/latex/errors.log"),
+        )
+        .unwrap();
+        let errors = parse_errors(&log)
+            .into_iter()
+            .map(|e| (e.message, e.line, e.context))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            [
+                (
+                    "Undefined control sequence.".into(),
+                    Some(12),
+                    r"$\foo".into()
+                ),
+                (
+                    "LaTeX Error: Environment foo undefined.".into(),
+                    Some(15),
+                    r"\begin{foo}".into()
+                ),
+                (
+                    "Missing $ inserted.".into(),
+                    Some(18),
+                    r"\end{displaymath}".into()
+                ),
+                ("Emergency stop.".into(), None, String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_errors() {
+        let log = "(./input.tex [1] (./input.aux) )\nOutput written on input.pdf (1 page).\n";
+        assert!(parse_errors(log).is_empty());
+    }
+}
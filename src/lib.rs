@@ -0,0 +1,1727 @@
+//! Renders LaTeX fragments with an actual LaTeX engine, for HTML pages.
+//!
+//! [`render`] typesets a list of [`Fragment`]s with a [`Config`] and returns the HTML of each of
+//! them, plus the HTML to include once in the page. [`render_document`] does the same for all the
+//! math and raw LaTeX in a Pandoc document, which is what the `just-latex` filter runs.
+
+use anyhow::{bail, Context, Result};
+use bytesize::ByteSize;
+use indoc::formatdoc;
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    io::{Cursor, Read, Write},
+    ops::Range,
+    path::Path,
+    process::Command,
+    sync::Arc,
+    thread,
+    time::Instant,
+    vec,
+};
+use tempfile::TempDir;
+use xz2::{read::XzEncoder, stream::LzmaOptions};
+
+use crate::cache::{Cache, CachedFragment, CachedImage};
+use crate::config::{Config, ErrorFallback, ErrorMode, Localization, Mode, Output, TemplateConfig};
+use crate::latex_log::LogError;
+use crate::pandoc::{Block, Inline, MathType, Pandoc};
+use crate::synctex::{Scanner, TeXBox};
+
+mod accessibility;
+mod cache;
+pub mod config;
+pub mod engine;
+mod latex_log;
+mod markers;
+pub mod mdbook;
+pub mod pandoc;
+pub mod pandoc_api;
+mod svg_optimize;
+mod svg_utils;
+mod synctex;
+pub mod watch;
+
+/// A LaTeX fragment to render.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fragment {
+    pub ty: FragmentType,
+    pub src: String,
+}
+
+/// The result of [`render`].
+#[derive(Clone, Debug)]
+pub struct Rendered {
+    /// HTML of each fragment, in order. It is `None` for fragments that failed to compile in
+    /// tolerant mode with the "math" fallback, which are best left to other means.
+    pub fragments: Vec<Option<String>>,
+    /// HTML to put once in the page, after all fragments: the decompressor script, the page styles
+    /// or the page SVGs, depending on the output. It may be empty.
+    pub final_html: String,
+}
+
+/// Renders fragments. They are typeset in the given order, in the same LaTeX document, so
+/// `DontShow` fragments may define macros used by later ones.
+pub fn render(config: &Config, fragments: &[Fragment]) -> Result<Rendered> {
+    config.sanity_check()?;
+    if fragments.is_empty() {
+        return Ok(Rendered {
+            fragments: vec![],
+            final_html: String::new(),
+        });
+    }
+    FragmentRenderer { config, fragments }.render_with_latex()
+}
+
+/// Renders a single formula as a standalone SVG document, cropped to it.
+pub fn render_svg(config: &Config, src: &str) -> Result<String> {
+    let config = Config {
+        output: Output::Svg,
+        crop_fragments: true,
+        ..config.clone()
+    };
+    config.sanity_check()?;
+    let fragments = [Fragment {
+        ty: FragmentType::InlineMath(Style::Plain),
+        src: src.into(),
+    }];
+    let renderer = FragmentRenderer {
+        config: &config,
+        fragments: &fragments,
+    };
+    renderer.standalone_svg(renderer.render_pages()?)
+}
+
+/// Renders all fragments in a Pandoc document, replacing their nodes by HTML, and appends the final
+/// HTML to the document.
+pub fn render_document(config: &Config, doc: &mut Pandoc) -> Result<()> {
+    let mut collector = Collector::default();
+    pandoc::walk(doc, &mut collector)?;
+    let rendered = render(config, &collector.fragments)?;
+    collector.apply(doc, rendered)
+}
+
+#[derive(Debug)]
+struct FragmentRenderer<'r> {
+    config: &'r Config,
+    fragments: &'r [Fragment],
+}
+
+/// Collects the fragments of a Pandoc document.
+#[derive(Debug, Default)]
+struct Collector {
+    fragments: Vec<Fragment>,
+    /// Addresses of the nodes holding each fragment (see [`pandoc`]).
+    refs: Vec<Vec<usize>>,
+    /// Styles of the containers being walked, innermost last.
+    styles: Vec<Style>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FragmentType {
+    /// For ordinary inline maths.
+    InlineMath(Style),
+    /// For display maths.
+    DisplayMath,
+    /// These will be included in the .tex file without being surrounded by "{}".
+    RawBlock,
+    /// For display maths starting with %dontshow. They are included in the tex files but not shown.
+    /// Use them for macro definitions.
+    DontShow,
+}
+
+impl FragmentType {
+    /// The type of display math, which may really be a raw block or a dont show, depending on the
+    /// comment it starts with.
+    pub fn display(src: &str) -> Self {
+        let trimmed = src.trim_start();
+        if trimmed.starts_with("%raw") {
+            FragmentType::RawBlock
+        } else if trimmed.starts_with("%dontshow") {
+            FragmentType::DontShow
+        } else {
+            FragmentType::DisplayMath
+        }
+    }
+}
+
+// On style: technically the correct way to handle styles is to handle find a set or orthogonal
+// properties and make a product type out of it. But this is not extensible in a sense that
+// orthogonality might be broken as new styles are considered. So instead we here just consider
+// style to be an ordered list of style elements. The problem with this approach, however, is that
+// it becomes difficult to compare equivalence of styles. Is Strong then Emph equivalent to Emph
+// then Strong? Is nested Quote equivalent to single Quote? Equivalence of styles is necessary to
+// deduplicate fragments and reduce size of our output. Of course for sane inputs this wouldn't be
+// a problem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StyleElement {
+    Header(u64),
+    Quote,
+    Strong,
+    Emph,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Inline math style.
+pub enum Style {
+    Plain,
+    Fancy {
+        base: Arc<Style>,
+        this: StyleElement,
+    },
+}
+
+impl fmt::Display for FragmentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentType::InlineMath(Style::Plain) => write!(f, "inline math"),
+            FragmentType::InlineMath(style) => write!(f, "inline math (in {})", style),
+            FragmentType::DisplayMath => write!(f, "display math"),
+            FragmentType::RawBlock => write!(f, "raw block"),
+            FragmentType::DontShow => write!(f, "%dontshow block"),
+        }
+    }
+}
+
+impl fmt::Display for Style {
+    /// Lists style elements from the outermost to the innermost, e.g. "header 2 > emph".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Style::Plain => Ok(()),
+            Style::Fancy { base, this } => {
+                if let Style::Fancy { .. } = **base {
+                    write!(f, "{} > ", base)?;
+                }
+                match this {
+                    StyleElement::Header(level) => write!(f, "header {}", level),
+                    StyleElement::Quote => write!(f, "quote"),
+                    StyleElement::Strong => write!(f, "strong"),
+                    StyleElement::Emph => write!(f, "emph"),
+                }
+            }
+        }
+    }
+}
+
+impl Style {
+    pub fn push(self, new: StyleElement) -> Self {
+        Self::Fancy {
+            base: Arc::new(self),
+            this: new,
+        }
+    }
+
+    fn template(&self, config: &TemplateConfig) -> String {
+        match self {
+            Style::Plain => config.inline_math_inner.clone(),
+            Style::Fancy { base, this } => {
+                let base_template = base.template(config);
+                let this_template = match this {
+                    StyleElement::Header(level) => &config.header[*level as usize - 1],
+                    StyleElement::Quote => &config.quote,
+                    StyleElement::Strong => &config.strong,
+                    StyleElement::Emph => &config.emph,
+                };
+                this_template.replace(&config.placeholder, &base_template)
+            }
+        }
+    }
+}
+
+/// A page SVG, either produced by dvisvgm in this run or taken from the cache.
+#[derive(Debug)]
+struct Page {
+    data: Vec<u8>,
+    /// SHA-256 of the data, which also names the page in the cache, so it must not depend on the
+    /// build of just-latex.
+    hash: [u8; 32],
+}
+
+impl Page {
+    fn new(data: Vec<u8>) -> Self {
+        let hash = Sha256::digest(&data).into();
+        Self { data, hash }
+    }
+
+    /// A unique class name for each svg is important because HTMLs from multiple posts may be put
+    /// together in the home page of a blog. Then the decompressing code of each page starts a
+    /// race, each trying to modify every fragment image.
+    fn class_name(&self) -> String {
+        format!("jl-{}", base64::encode(&self.hash[..8]))
+    }
+
+    /// Same as the class name, but encoded to be safe in CSS selectors, file names and URLs.
+    fn css_class(&self) -> String {
+        format!(
+            "jl-{}",
+            base64::encode_config(&self.hash[..8], base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Name of the page file in "external" output.
+    fn file_name(&self) -> String {
+        format!("{}.svg", self.css_class())
+    }
+
+    /// Name of the page in the cache. Unlike the class name, this has to be a valid file name.
+    fn cache_name(&self) -> String {
+        hex(&self.hash)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Adds a page to the list unless an identical one is already present, and returns its index.
+fn add_page(pages: &mut Vec<Page>, page: Page) -> usize {
+    match pages.iter().position(|p| p.hash == page.hash) {
+        Some(idx) => idx,
+        None => {
+            pages.push(page);
+            pages.len() - 1
+        }
+    }
+}
+
+/// LaTeX failed to compile the document, with errors traced back to fragments.
+#[derive(Debug)]
+struct CompileError {
+    errors: Vec<FragmentError>,
+}
+
+#[derive(Debug)]
+struct FragmentError {
+    /// Index of the offending fragment, or none if the error is outside of any fragment (e.g. in
+    /// the preamble).
+    fragment: Option<usize>,
+    /// Description of the offending fragment, see [`FragmentType`]'s `Display` impl.
+    description: String,
+    /// Source of the offending fragment, as written in the document.
+    src: String,
+    error: LogError,
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in {}", self.description)?;
+        if !self.src.is_empty() {
+            write!(f, " `{}`", self.src)?;
+        }
+        write!(f, ": {}", self.error.message)?;
+        if let Some(line) = self.error.line {
+            write!(f, " (source.tex, line {}", line)?;
+            if !self.error.context.is_empty() {
+                write!(f, ", near `{}`", self.error.context)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LaTeX reported {} error(s):", self.errors.len())?;
+        for error in &self.errors {
+            write!(f, "\n- {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Region occupied by a fragment on a page, in TeX coordinates.
+#[derive(Clone, Debug, PartialEq)]
+struct Region {
+    x_range: (f64, f64),
+    y_range: (f64, f64),
+    baseline: f64,
+    baseline_width: f64,
+}
+
+/// Computes the regions of a fragment from the SyncTeX boxes of the lines it spans.
+fn regions_from_synctex(
+    scanner: &Scanner,
+    line_range: Range<usize>,
+    seen_boxes: &mut HashSet<TeXBox>,
+) -> BTreeMap<u32, Region> {
+    let mut regions: BTreeMap<u32, Region> = BTreeMap::new();
+
+    for line in line_range {
+        for tb in scanner.query(line) {
+            let area = tb.width * (tb.height + tb.depth);
+            if area.into_inner() <= 1e-6 {
+                // Skip zero-area boxes. They may be generated by the TeX page breaker and
+                // do not actually correspond to anything in our source file. Also they
+                // wouldn't contribute to updating the region of the page anyways.
+                continue;
+            }
+            if seen_boxes.contains(&tb) {
+                // Continue if we have seen this box -- then probably that's SyncTeX's
+                // fault
+                continue;
+            }
+            seen_boxes.insert(tb.clone());
+
+            let (x_low, x_high) = (tb.h.into_inner(), (tb.h + tb.width).into_inner());
+            let (y_low, y_high) = (
+                (tb.v - tb.height).into_inner(),
+                (tb.v + tb.depth).into_inner(),
+            );
+            regions
+                .entry(tb.page)
+                .and_modify(|r| {
+                    r.x_range = (r.x_range.0.min(x_low), r.x_range.1.max(x_high));
+                    r.y_range = (r.y_range.0.min(y_low), r.y_range.1.max(y_high));
+                    if tb.width.into_inner() > r.baseline_width {
+                        r.baseline_width = tb.width.into_inner();
+                        r.baseline = tb.v.into_inner();
+                    }
+                })
+                .or_insert_with(|| Region {
+                    x_range: (x_low, x_high),
+                    y_range: (y_low, y_high),
+                    baseline: tb.v.into(),
+                    baseline_width: tb.width.into(),
+                });
+        }
+    }
+    regions
+}
+
+/// The pages rendered for the fragments, and where each fragment is found in them.
+struct Pages {
+    pages: Vec<Page>,
+    /// Images of each fragment, if it was rendered.
+    rendered: Vec<Option<Vec<Image>>>,
+    /// Fragments that failed to compile in tolerant mode, with the error.
+    failures: Vec<Option<FragmentError>>,
+}
+
+/// Where a rendered fragment is found in a page SVG.
+#[derive(Clone, Debug)]
+struct Image {
+    /// Index of the page in the list of pages.
+    page: usize,
+    /// x, y, width and height of the fragment in SVG coordinates.
+    view_box: (f64, f64, f64, f64),
+    /// Distance between the baseline and the bottom of the image. Only meaningful for inline
+    /// fragments.
+    depth: f64,
+}
+
+impl FragmentRenderer<'_> {
+    /// Expands the template of a fragment into the LaTeX source that is written to the .tex file.
+    ///
+    /// If `marker` is given, the fragment is surrounded by position markers with this id (see
+    /// [`markers`]).
+    fn expand(&self, item: &Fragment, marker: Option<usize>) -> String {
+        let template_config = &self.config.template;
+        let expanded = match &item.ty {
+            FragmentType::InlineMath(style) => {
+                let mut inner = style
+                    .template(template_config)
+                    .replace(&template_config.placeholder, &item.src);
+                if let Some(marker) = marker {
+                    inner = markers::wrap_inline(marker, &inner);
+                }
+                template_config
+                    .inline_math
+                    .replace(&template_config.placeholder, &inner)
+            }
+            FragmentType::DisplayMath => template_config
+                .display_math
+                .replace(&template_config.placeholder, &item.src),
+            FragmentType::RawBlock | FragmentType::DontShow => item.src.clone(),
+        };
+        let expanded = expanded.trim_end();
+        match (&item.ty, marker) {
+            (FragmentType::DisplayMath | FragmentType::RawBlock, Some(marker)) => {
+                markers::wrap_block(marker, expanded)
+            }
+            _ => expanded.into(),
+        }
+    }
+
+    /// Generates the .tex source for the fragments at `indices`, along with the lines occupied by
+    /// each of them.
+    fn generate_latex_with_line_mappings(&self, indices: &[usize]) -> (String, Vec<Range<usize>>) {
+        let mut lines: Vec<Range<usize>> = vec![];
+        let mut output = String::new();
+        let preamble_trimmed = self.config.preamble.trim_end();
+        output.push_str(preamble_trimmed);
+        output.push('\n');
+        let mut current_line = preamble_trimmed.lines().count() + 1;
+        let use_markers = self.config.localization == Localization::Markers;
+        if use_markers {
+            output.push_str(markers::SETUP);
+            output.push('\n');
+            current_line += markers::SETUP.lines().count();
+        }
+        for &idx in indices {
+            let expanded = self.expand(&self.fragments[idx], use_markers.then_some(idx));
+            let start_line = current_line;
+            output.push_str(&expanded);
+            current_line += expanded.lines().count();
+            lines.push(start_line..current_line);
+            output.push_str("\n\n");
+            current_line += 1;
+        }
+        output.push_str(&self.config.postamble);
+        (output, lines)
+    }
+
+    /// Computes the cache key of every fragment.
+    ///
+    /// Besides the fragment itself, the key covers everything that could change its rendering: the
+    /// preamble, the engine settings, and all the `DontShow` and `RawBlock` fragments before it,
+    /// since they may (re)define macros.
+    fn cache_keys(&self) -> Result<Vec<String>> {
+        // Keys must stay the same across builds and Rust versions, so everything is hashed by its
+        // JSON representation, and strings are prefixed with their length to keep them apart.
+        let mut context = Sha256::new();
+        let settings = serde_json::to_vec(&(
+            env!("CARGO_PKG_VERSION"),
+            &self.config.preamble,
+            &self.config.postamble,
+            self.config.engine,
+            &self.config.latex,
+            &self.config.dvisvgm,
+            self.config.mode,
+            self.config.localization,
+            self.config.y_range_tol,
+            self.config.y_range_margin,
+        ))?;
+        context.update(&settings);
+        let update = |hasher: &mut Sha256, s: &str| {
+            hasher.update((s.len() as u64).to_le_bytes());
+            hasher.update(s);
+        };
+        Ok(self
+            .fragments
+            .iter()
+            .map(|item| {
+                let expanded = self.expand(item, None);
+                let mut hasher = context.clone();
+                update(&mut hasher, &expanded);
+                if let FragmentType::RawBlock | FragmentType::DontShow = item.ty {
+                    update(&mut context, &expanded);
+                }
+                hex(&hasher.finalize())
+            })
+            .collect())
+    }
+
+    /// Renders the fragments with LaTeX, or takes them from the cache, and returns their HTML along
+    /// with the final HTML of the page.
+    fn render_with_latex(&self) -> Result<Rendered> {
+        let Pages {
+            pages,
+            rendered,
+            failures,
+        } = self.render_pages()?;
+        let svg_data = self.page_svgs(&pages)?;
+        // What goes before "#svgView(...)" in the src of <img>s of each page.
+        let page_urls = match self.config.output {
+            Output::External => {
+                let folder = Path::new(self.config.asset_folder.as_ref().unwrap());
+                fs::create_dir_all(folder)?;
+                let mut urls = vec![];
+                for (svg, page) in svg_data.iter().zip(&pages) {
+                    let path = folder.join(page.file_name());
+                    fs::write(&path, svg)
+                        .with_context(|| format!("writing SVG to {}", path.display()))?;
+                    urls.push(format!(
+                        "{}{}",
+                        self.config.asset_url_prefix,
+                        page.file_name()
+                    ));
+                }
+                urls
+            }
+            Output::DataUri => svg_data
+                .iter()
+                .map(|svg| format!("data:image/svg+xml;base64,{}", base64::encode(svg)))
+                .collect(),
+            // The inline decompressor script swaps in blob URLs later on, and "data-uri-css" does
+            // not use URLs in <img>s at all.
+            _ => vec![String::new(); pages.len()],
+        };
+        // In "data-uri-css" output, fragments are windows into the page set as background image,
+        // so the geometry of pages is needed.
+        let page_view_boxes = if self.config.output == Output::DataUriCss {
+            svg_data
+                .iter()
+                .map(|svg| svg_utils::view_box(svg))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            vec![]
+        };
+
+        // Contents of the page SVGs to be inlined in "svg" output.
+        let page_markups = if self.config.output == Output::Svg {
+            svg_data
+                .iter()
+                .zip(&pages)
+                .map(|(svg, page)| svg_utils::inline_markup(svg, &page.css_class()))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            vec![]
+        };
+
+        let mut htmls = vec![];
+        for ((item, images), failure) in self.fragments.iter().zip(rendered).zip(failures) {
+            if let Some(failure) = failure {
+                eprintln!("warning: fragment left unrendered: {}", failure);
+                if let FragmentType::DontShow = item.ty {
+                    // Still not to be shown, fall through.
+                } else if self.config.error_fallback == ErrorFallback::Code {
+                    let html = format!(
+                        r#"<code class="jl-error" title="{title}">{src}</code>"#,
+                        title = html_escape::encode_double_quoted_attribute(&failure.error.message),
+                        src = html_escape::encode_text(&item.src),
+                    );
+                    htmls.push(Some(html));
+                    continue;
+                } else {
+                    // Leave the original Math node as it is, for Pandoc to render.
+                    htmls.push(None);
+                    continue;
+                }
+            }
+            if let FragmentType::DontShow = item.ty {
+                // Skip dont shows.
+                htmls.push(Some(String::new()));
+                continue;
+            }
+
+            let description = match item.ty {
+                FragmentType::InlineMath(_) | FragmentType::DisplayMath => {
+                    accessibility::describe(&self.config.accessibility, &item.src)?
+                }
+                _ => accessibility::describe_raw_block(),
+            };
+            let label = html_escape::encode_double_quoted_attribute(&description.label);
+            // Blocks are labelled as a whole, and fragments with MathML are read from it, so their
+            // images are hidden from screen readers.
+            let hidden = description.mathml.is_some()
+                || matches!(item.ty, FragmentType::DisplayMath | FragmentType::RawBlock);
+            let a11y = if hidden {
+                r#"aria-hidden="true""#.to_string()
+            } else {
+                format!(r#"role="img" aria-label="{}""#, label)
+            };
+
+            let mut imgs = vec![];
+            for Image {
+                page,
+                view_box,
+                depth,
+            } in images.unwrap()
+            {
+                let extra_style = match item.ty {
+                    FragmentType::InlineMath(_) => format!(
+                        "top:{depth:.2}pt;margin-top:{neg_depth:.2}pt;position:relative;{extra_style}",
+                        depth = depth - self.config.baseline_rise,
+                        neg_depth = self.config.baseline_rise - depth,
+                        extra_style = self.config.extra_style_inline
+                    ),
+                    FragmentType::DisplayMath | FragmentType::RawBlock => {
+                        self.config.extra_style_display.clone()
+                    }
+                    FragmentType::DontShow => unreachable!(),
+                };
+                let ty = if let FragmentType::InlineMath(_) = item.ty {
+                    "inline"
+                } else {
+                    "display"
+                };
+                if self.config.output == Output::DataUriCss {
+                    let page_view_box = page_view_boxes[page];
+                    imgs.push(formatdoc!(
+                        r##"<span class="{class_name} jl-{ty}" {a11y}
+                             style="width:{width:.2}pt;height:{height:.2}pt;
+                             display:inline-block;background-position:{x:.2}pt {y:.2}pt;
+                             background-size:{page_width:.2}pt {page_height:.2}pt;{extra_style}"></span>"##,
+                        x = page_view_box.0 - view_box.0,
+                        y = page_view_box.1 - view_box.1,
+                        width = view_box.2,
+                        height = view_box.3,
+                        page_width = page_view_box.2,
+                        page_height = page_view_box.3,
+                        ty = ty,
+                        class_name = pages[page].css_class(),
+                        a11y = a11y,
+                        extra_style = extra_style
+                    ));
+                    continue;
+                }
+                if self.config.output == Output::Svg {
+                    // Pages are defined once in the final node, since a (cropped) page may be
+                    // shown by several nodes of a deduplicated fragment and must not repeat its ids.
+                    let content = format!(r##"<use href="#{}"/>"##, pages[page].css_class());
+                    imgs.push(formatdoc!(
+                        r##"<svg class="{class_name} jl-{ty}" {a11y}
+                             viewBox="{x:.2} {y:.2} {width:.2} {height:.2}"
+                             style="width:{width:.2}pt;height:{height:.2}pt;
+                             display:inline;{extra_style}">{content}</svg>"##,
+                        x = view_box.0,
+                        y = view_box.1,
+                        width = view_box.2,
+                        height = view_box.3,
+                        ty = ty,
+                        class_name = pages[page].css_class(),
+                        a11y = a11y,
+                        extra_style = extra_style,
+                        content = content
+                    ));
+                    continue;
+                }
+                imgs.push(formatdoc!(
+                    r##"<img src="{url}#svgView(viewBox({x:.2},{y:.2},{width:.2},{height:.2}))"
+                         class="{class_name} jl-{ty}" {alt}
+                         style="width:{width:.2}pt;height:{height:.2}pt;
+                         display:inline;{extra_style}">"##,
+                    url = page_urls[page],
+                    x = view_box.0,
+                    y = view_box.1,
+                    width = view_box.2,
+                    height = view_box.3,
+                    ty = ty,
+                    class_name = pages[page].class_name(),
+                    alt = if hidden {
+                        r#"alt="" aria-hidden="true""#.to_string()
+                    } else {
+                        format!(r#"alt="{}""#, label)
+                    },
+                    extra_style = extra_style
+                ));
+            }
+            let mathml = match &description.mathml {
+                Some(mathml) => format!(
+                    r#"<span class="jl-mathml" style="position:absolute;width:1px;height:1px;overflow:hidden;clip:rect(0 0 0 0);">{}</span>"#,
+                    mathml
+                ),
+                None => String::new(),
+            };
+            let html = match item.ty {
+                FragmentType::InlineMath(_) => format!("{}{}", mathml, imgs.join("")),
+                FragmentType::DisplayMath | FragmentType::RawBlock => {
+                    let a11y = if description.mathml.is_some() {
+                        String::new()
+                    } else {
+                        format!(r#" role="img" aria-label="{}""#, label)
+                    };
+                    format!(
+                        r#"<div class="jl-display-div"{} style="text-align:center;">{}{}</div>"#,
+                        a11y,
+                        mathml,
+                        imgs.join("<br>")
+                    )
+                }
+                FragmentType::DontShow => unreachable!(),
+            };
+            htmls.push(Some(html));
+        }
+
+        let final_code = match self.config.output {
+            Output::Inline => self.decompress_script(&svg_data, &pages)?,
+            Output::DataUriCss => {
+                let mut style = String::from("<style>");
+                for (svg, page) in svg_data.iter().zip(&pages) {
+                    style.push_str(&format!(
+                        r#".{}{{background-image:url("data:image/svg+xml;base64,{}")}}"#,
+                        page.css_class(),
+                        base64::encode(svg)
+                    ));
+                }
+                style.push_str("</style>");
+                style
+            }
+            Output::Svg => {
+                let mut defs = String::from(
+                    r#"<svg aria-hidden="true" style="position:absolute;width:0;height:0;overflow:hidden"><defs>"#,
+                );
+                for (markup, page) in page_markups.iter().zip(&pages) {
+                    defs.push_str(&format!(
+                        r#"<g id="{}" fill="currentColor">{}</g>"#,
+                        page.css_class(),
+                        markup
+                    ));
+                }
+                defs.push_str("</defs></svg>");
+                defs
+            }
+            _ => String::new(),
+        };
+        Ok(Rendered {
+            fragments: htmls,
+            final_html: final_code,
+        })
+    }
+
+    /// Turns the cropped page of the first fragment into a standalone SVG document.
+    fn standalone_svg(&self, pages: Pages) -> Result<String> {
+        let src = &self.fragments[0].src;
+        if let Some(failure) = &pages.failures[0] {
+            bail!("'{}' failed to compile: {}", src, failure);
+        }
+        let page = match pages.rendered[0].as_deref() {
+            Some([image, ..]) => image.page,
+            _ => bail!("'{}' rendered to nothing", src),
+        };
+        let svg_data = self.page_svgs(&pages.pages)?;
+        svg_utils::standalone(&svg_data[page])
+    }
+
+    /// Renders the fragments with LaTeX, or takes them from the cache, into pages, cropped if
+    /// `crop_fragments` is set.
+    fn render_pages(&self) -> Result<Pages> {
+        let cache = match &self.config.cache_folder {
+            Some(folder) => Some(Cache::open(folder)?),
+            None => None,
+        };
+        let keys = self.cache_keys()?;
+        let mut pages: Vec<Page> = vec![];
+        let mut rendered: Vec<Option<Vec<Image>>> = vec![None; self.fragments.len()];
+        // Fragments that failed to compile in tolerant mode, with the error.
+        let mut failures: Vec<Option<FragmentError>> =
+            self.fragments.iter().map(|_| None).collect();
+
+        if let Some(cache) = &cache {
+            for ((item, key), rendered) in self.fragments.iter().zip(&keys).zip(&mut rendered) {
+                if let FragmentType::DontShow = item.ty {
+                    continue;
+                }
+                if let Some((entry, data)) = cache.get(key) {
+                    let mut images = vec![];
+                    for (image, data) in entry.images.into_iter().zip(data) {
+                        images.push(Image {
+                            page: add_page(&mut pages, Page::new(data)),
+                            view_box: image.view_box,
+                            depth: image.depth,
+                        });
+                    }
+                    *rendered = Some(images);
+                }
+            }
+        }
+
+        let misses = self
+            .fragments
+            .iter()
+            .zip(&rendered)
+            .filter(|(item, rendered)| {
+                !matches!(item.ty, FragmentType::DontShow) && rendered.is_none()
+            })
+            .count();
+        if cache.is_some() {
+            eprintln!(
+                "{} fragments found in cache, {} to compile",
+                rendered.iter().filter(|r| r.is_some()).count(),
+                misses
+            );
+        }
+
+        if misses > 0 {
+            // Macro definitions are replayed in every compilation, whether they hit or not.
+            let indices = (0..self.fragments.len())
+                .filter(|&i| {
+                    rendered[i].is_none()
+                        || matches!(
+                            self.fragments[i].ty,
+                            FragmentType::DontShow | FragmentType::RawBlock
+                        )
+                })
+                .collect::<Vec<_>>();
+            let first_new_page = pages.len();
+            let compiled = self.compile_sharded(&indices, &mut pages)?;
+            if let Some(cache) = &cache {
+                for page in &pages[first_new_page..] {
+                    cache.put_page(&page.cache_name(), &page.data)?;
+                }
+            }
+            for (idx, images) in indices.into_iter().zip(compiled) {
+                let images = match images {
+                    Ok(images) => images,
+                    Err(error) => {
+                        failures[idx] = Some(*error);
+                        continue;
+                    }
+                };
+                if let Some(cache) = &cache {
+                    if !matches!(self.fragments[idx].ty, FragmentType::DontShow) {
+                        let entry = CachedFragment {
+                            images: images
+                                .iter()
+                                .map(|image| CachedImage {
+                                    page: pages[image.page].cache_name(),
+                                    view_box: image.view_box,
+                                    depth: image.depth,
+                                })
+                                .collect(),
+                        };
+                        cache.put(&keys[idx], &entry)?;
+                    }
+                }
+                rendered[idx] = Some(images);
+            }
+        }
+
+        if self.config.crop_fragments {
+            // Every image becomes a page of its own, holding just what the image shows.
+            let mut trees: HashMap<usize, usvg::Tree> = HashMap::new();
+            let mut cropped_pages: Vec<Page> = vec![];
+            for image in rendered.iter_mut().flatten().flatten() {
+                let tree = match trees.entry(image.page) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        entry.insert(svg_utils::parse_to_tree(&pages[image.page].data)?)
+                    }
+                };
+                let cropped = svg_utils::crop(tree, image.view_box)?;
+                let data = cropped.to_string(&usvg::XmlOptions::default());
+                image.page = add_page(&mut cropped_pages, Page::new(data.into_bytes()));
+            }
+            pages = cropped_pages;
+        }
+        Ok(Pages {
+            pages,
+            rendered,
+            failures,
+        })
+    }
+
+    /// The final SVGs of the pages: optimized, converted to plain paths or with their colors
+    /// remapped, depending on the config.
+    fn page_svgs<'p>(&self, pages: &'p [Page]) -> Result<Vec<Cow<'p, [u8]>>> {
+        let svg_data = if self.config.optimizer.enabled {
+            pages
+                .iter()
+                .map(|page| -> Result<Cow<[u8]>> {
+                    Ok(Cow::Owned(svg_optimize::optimize(
+                        &svg_utils::parse_to_tree(&page.data)?,
+                        self.config.optimizer.eps,
+                    )?))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else if (self.config.output == Output::Svg || !self.config.colors.rules.is_empty())
+            && !self.config.crop_fragments
+        {
+            // Inlined SVGs share the stylesheet of the document, and color rules need explicit
+            // colors on every path, so the fonts and classes defined by dvisvgm are turned into
+            // plain paths first. Cropped pages already are.
+            pages
+                .iter()
+                .map(|page| -> Result<Cow<[u8]>> {
+                    let tree = svg_utils::parse_to_tree(&page.data)?;
+                    Ok(Cow::Owned(
+                        tree.to_string(&usvg::XmlOptions::default()).into_bytes(),
+                    ))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            pages
+                .iter()
+                .map(|page| Cow::Borrowed(&page.data[..]))
+                .collect()
+        };
+        let svg_data = if self.config.colors.rules.is_empty() {
+            svg_data
+        } else {
+            svg_data
+                .iter()
+                .map(|svg| -> Result<Cow<[u8]>> {
+                    Ok(Cow::Owned(svg_utils::remap_colors(
+                        svg,
+                        &self.config.colors.rules,
+                    )?))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        Ok(svg_data)
+    }
+
+    /// Creates the <script> that decompresses the pages and swaps them into the <img>s, for the
+    /// "inline" output.
+    fn decompress_script(&self, svg_data: &[Cow<[u8]>], pages: &[Page]) -> Result<String> {
+        let lzma_options = LzmaOptions::new_preset(9)?;
+        let mut decompress_script = String::new();
+        for (i, (svg, page)) in svg_data.iter().zip(pages).enumerate() {
+            let start = Instant::now();
+            let original_size = svg.len();
+            let mut svg_compressor = XzEncoder::new_stream(
+                Cursor::new(svg),
+                xz2::stream::Stream::new_lzma_encoder(&lzma_options)?,
+            );
+            let mut svg_compressed = vec![];
+            svg_compressor.read_to_end(&mut svg_compressed)?;
+            let svg_encoded = base64::encode(svg_compressed);
+            decompress_script.push_str(&formatdoc!(
+                r##"
+                    var w{page}=new Worker(s);
+                    w{page}.onmessage=f("{class_name}");
+                    w{page}.postMessage("{svg}");
+                "##,
+                page = i + 1,
+                svg = svg_encoded,
+                class_name = page.class_name()
+            ));
+
+            eprintln!(
+                "SVG for page {} compressed from {} down to {} (base64 encoded) in {}s",
+                i + 1,
+                ByteSize::b(original_size as u64),
+                ByteSize::b(svg_encoded.len() as u64),
+                start.elapsed().as_secs_f64()
+            );
+        }
+
+        Ok(formatdoc!(
+            r##"
+            <script {extra_attribs}>
+                (function(){{
+                    var s=URL.createObjectURL(new Blob(['"function"==typeof importScripts&&(importScripts("{lzma_js_path}"),onmessage=function(a){{LZMA.decompress(Uint8Array.from(atob(a.data),function(a){{return a.charCodeAt(0)}}),function(a,b){{postMessage(a)}})}})'], {{type: "text/javascript"}}));
+                    var f=function(a){{return function(e){{for(var f=URL.createObjectURL(new Blob([typeof e.data==="string"?e.data:new Uint8Array(e.data)],{{type:"image/svg+xml"}})),c=document.getElementsByClassName(a),b=0;b<c.length;b++){{var d=c[b].src.indexOf("#");-1!=d&&(c[b].src=f+c[b].src.substring(d))}}}}}};
+                    {decompress_script}
+                }}());
+            </script>
+            "##,
+            extra_attribs = self.config.script_extra_attributes,
+            lzma_js_path = self.config.lzma_js_path,
+            decompress_script = decompress_script
+        ))
+    }
+
+    /// Maps errors found in the LaTeX log back to the fragments at `indices`, given the lines they
+    /// occupy in the .tex file.
+    fn trace_errors(
+        &self,
+        indices: &[usize],
+        lines: &[Range<usize>],
+        errors: Vec<LogError>,
+    ) -> CompileError {
+        let first_line = lines.first().map_or(usize::MAX, |r| r.start);
+        let errors = errors
+            .into_iter()
+            .map(|error| {
+                // TeX often detects errors only at the end of the paragraph, that is, the blank
+                // line following a fragment, so it is attributed to the fragment as well.
+                let pos = error
+                    .line
+                    .and_then(|line| lines.iter().position(|r| (r.start..=r.end).contains(&line)));
+                match pos {
+                    Some(pos) => {
+                        let item = &self.fragments[indices[pos]];
+                        FragmentError {
+                            fragment: Some(indices[pos]),
+                            description: item.ty.to_string(),
+                            src: item.src.clone(),
+                            error,
+                        }
+                    }
+                    None => FragmentError {
+                        fragment: None,
+                        description: match error.line {
+                            Some(line) if line < first_line => "preamble".into(),
+                            Some(_) => "postamble".into(),
+                            None => "unknown location".into(),
+                        },
+                        src: String::new(),
+                        error,
+                    },
+                }
+            })
+            .collect();
+        CompileError { errors }
+    }
+
+    /// Compiles the fragments at `indices`, split into shards that are compiled concurrently in
+    /// separate working directories (see [`Config::jobs`]). Resulting pages are appended to `pages`.
+    ///
+    /// Shown fragments are distributed among the shards in contiguous chunks, while `DontShow` and
+    /// `RawBlock` fragments are replayed in every shard, since they may define macros.
+    fn compile_sharded(
+        &self,
+        indices: &[usize],
+        pages: &mut Vec<Page>,
+    ) -> Result<Vec<Result<Vec<Image>, Box<FragmentError>>>> {
+        let is_definition = |idx: &usize| {
+            matches!(
+                self.fragments[*idx].ty,
+                FragmentType::DontShow | FragmentType::RawBlock
+            )
+        };
+        let candidates = indices
+            .iter()
+            .copied()
+            .filter(|idx| !is_definition(idx))
+            .collect::<Vec<_>>();
+        let jobs = self
+            .config
+            .jobs
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+        let n_shards = jobs.min(candidates.len()).max(1);
+        if n_shards == 1 {
+            return self.compile_shard(indices, None, pages);
+        }
+
+        let shards = candidates
+            .chunks(candidates.len().div_ceil(n_shards))
+            .map(|chunk| {
+                let mut shard = indices
+                    .iter()
+                    .copied()
+                    .filter(is_definition)
+                    .chain(chunk.iter().copied())
+                    .collect::<Vec<_>>();
+                shard.sort_unstable();
+                shard
+            })
+            .collect::<Vec<_>>();
+        eprintln!(
+            "compiling {} fragments in {} shards",
+            candidates.len(),
+            shards.len()
+        );
+        let results = thread::scope(|scope| {
+            let handles = shards
+                .iter()
+                .enumerate()
+                .map(|(k, shard)| {
+                    scope.spawn(move || -> Result<_> {
+                        let mut shard_pages = vec![];
+                        let results = self.compile_shard(shard, Some(k), &mut shard_pages)?;
+                        Ok((shard_pages, results))
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut merged = HashMap::new();
+        for (shard, (shard_pages, results)) in shards.iter().zip(results) {
+            let page_map = shard_pages
+                .into_iter()
+                .map(|page| add_page(pages, page))
+                .collect::<Vec<_>>();
+            for (&idx, result) in shard.iter().zip(results) {
+                // Definitions are compiled in every shard, keep the first.
+                merged.entry(idx).or_insert_with(|| {
+                    result.map(|images| {
+                        images
+                            .into_iter()
+                            .map(|image| Image {
+                                page: page_map[image.page],
+                                ..image
+                            })
+                            .collect()
+                    })
+                });
+            }
+        }
+        Ok(indices
+            .iter()
+            .map(|idx| merged.remove(idx).unwrap())
+            .collect())
+    }
+
+    /// Compiles a single shard, in strict or tolerant mode depending on the configuration.
+    fn compile_shard(
+        &self,
+        indices: &[usize],
+        shard: Option<usize>,
+        pages: &mut Vec<Page>,
+    ) -> Result<Vec<Result<Vec<Image>, Box<FragmentError>>>> {
+        if self.config.error_mode == ErrorMode::Tolerant {
+            self.compile_tolerant(indices, shard, pages)
+        } else {
+            Ok(self
+                .compile(indices, shard, pages)?
+                .into_iter()
+                .map(Ok)
+                .collect())
+        }
+    }
+
+    /// Like [`Self::compile`], but fragments that LaTeX fails on are left out instead of failing
+    /// the whole document. Returns, for each of `indices`, either its images or the error.
+    ///
+    /// Only the first error reported by LaTeX is trusted, since the following ones are often
+    /// consequences of it: its fragment is removed and the rest compiled again. Errors that cannot
+    /// be attributed to a fragment (e.g. a group left open, only detected at the end of the
+    /// document) are tracked down by bisection.
+    fn compile_tolerant(
+        &self,
+        indices: &[usize],
+        shard: Option<usize>,
+        pages: &mut Vec<Page>,
+    ) -> Result<Vec<Result<Vec<Image>, Box<FragmentError>>>> {
+        let mut failures: BTreeMap<usize, FragmentError> = BTreeMap::new();
+        loop {
+            let remaining = indices
+                .iter()
+                .copied()
+                .filter(|idx| !failures.contains_key(idx))
+                .collect::<Vec<_>>();
+            let error = match self.compile(&remaining, shard, pages) {
+                Ok(compiled) => {
+                    let mut compiled = remaining
+                        .into_iter()
+                        .zip(compiled)
+                        .collect::<BTreeMap<_, _>>();
+                    return Ok(indices
+                        .iter()
+                        .map(|idx| match failures.remove(idx) {
+                            Some(failure) => Err(Box::new(failure)),
+                            None => Ok(compiled.remove(idx).unwrap()),
+                        })
+                        .collect());
+                }
+                // Errors other than LaTeX's are not the fault of any fragment.
+                Err(error) => error.downcast::<CompileError>()?,
+            };
+            let mut first = error.errors.into_iter().next().unwrap();
+            match first.fragment {
+                Some(idx) => {
+                    failures.insert(idx, first);
+                }
+                None => {
+                    let found = self.bisect_failures(&remaining, shard)?;
+                    if found.is_empty() {
+                        // Every fragment compiles on its own, but not together. Give up.
+                        first.description = format!("{} (tolerant mode)", first.description);
+                        return Err(CompileError {
+                            errors: vec![first],
+                        }
+                        .into());
+                    }
+                    failures.extend(found.into_iter().map(|f| (f.fragment.unwrap(), f)));
+                }
+            }
+        }
+    }
+
+    /// Finds the fragments among `indices` that fail to compile on their own, by bisection.
+    /// `DontShow` fragments are included in every attempt since others may depend on them.
+    fn bisect_failures(
+        &self,
+        indices: &[usize],
+        shard: Option<usize>,
+    ) -> Result<Vec<FragmentError>> {
+        let (definitions, candidates): (Vec<usize>, Vec<usize>) = indices
+            .iter()
+            .partition(|&&idx| matches!(self.fragments[idx].ty, FragmentType::DontShow));
+        let mut failures = vec![];
+        // The definitions are tried alone first: if the error is theirs or the preamble's, every
+        // fragment would fail on its own, costing two compilations per fragment to find out.
+        let mut groups = vec![candidates, vec![]];
+        while let Some(group) = groups.pop() {
+            let mut attempt = definitions.clone();
+            attempt.extend(&group);
+            attempt.sort_unstable();
+            // Pages from these attempts are thrown away.
+            let error = match self.compile(&attempt, shard, &mut vec![]) {
+                Ok(_) => continue,
+                Err(error) => error.downcast::<CompileError>()?,
+            };
+            match group[..] {
+                // Even the definitions alone do not compile.
+                [] => return Err(error.into()),
+                [idx] => {
+                    let item = &self.fragments[idx];
+                    let first = error.errors.into_iter().next().unwrap();
+                    failures.push(FragmentError {
+                        fragment: Some(idx),
+                        description: item.ty.to_string(),
+                        src: item.src.clone(),
+                        error: first.error,
+                    });
+                }
+                _ => {
+                    let (left, right) = group.split_at(group.len() / 2);
+                    groups.push(right.to_vec());
+                    groups.push(left.to_vec());
+                }
+            }
+        }
+        Ok(failures)
+    }
+
+    /// Compiles the fragments at `indices` with LaTeX and locates them in the resulting SVGs.
+    ///
+    /// Resulting pages are appended to `pages`. Returns the images of each fragment, in the order
+    /// of `indices` (empty for `DontShow` fragments). Shards get their own subfolder of the output
+    /// folder.
+    fn compile(
+        &self,
+        indices: &[usize],
+        shard: Option<usize>,
+        pages: &mut Vec<Page>,
+    ) -> Result<Vec<Vec<Image>>> {
+        // In TeX 1 in = 72.72 pt = 72 bp, while in SVG 1 in = 72 pt.
+        // Due to different definitions of pt we need a small scaling factor here.
+        // See https://github.com/mgieseki/dvisvgm/issues/185
+        const TEX2SVG_SCALING: f64 = 72.0 / 72.27;
+
+        let (source_str, lines) = self.generate_latex_with_line_mappings(indices);
+        let mut working_dir = match self.config.output_folder {
+            Some(_) => None,
+            None => Some(TempDir::new()?),
+        };
+        let working_path = match (&working_dir, shard) {
+            (Some(working_dir), _) => working_dir.path().to_path_buf(),
+            (None, None) => Path::new(self.config.output_folder.as_ref().unwrap()).to_path_buf(),
+            (None, Some(shard)) => {
+                let path = Path::new(self.config.output_folder.as_ref().unwrap())
+                    .join(format!("shard-{}", shard));
+                fs::create_dir_all(&path)?;
+                path
+            }
+        }
+        .canonicalize()?;
+        if self.config.keep_temp {
+            if let Some(working_dir) = working_dir.take() {
+                eprintln!("Keeping LaTeX files in {}", working_dir.keep().display());
+            }
+        }
+        let source_path = working_path.join("source.tex");
+
+        // eprintln!("{}", source_str);
+        {
+            let mut source = File::create(&source_path)?;
+            source.write_all(source_str.as_bytes())?;
+        }
+        let engine = self.config.engine;
+        let pdf_path = working_path.join(format!("source.{}", self.config.mode.name()));
+        let latex_command = Command::new(self.config.latex_executable())
+            .args(engine.latex_args(self.config.mode))
+            .arg(&source_path)
+            .current_dir(&working_path)
+            .output()?;
+        if !latex_command.status.success() {
+            let log = fs::read(working_path.join("source.log")).unwrap_or_default();
+            let errors = latex_log::parse_errors(&String::from_utf8_lossy(&log));
+            if errors.is_empty() {
+                // Nothing we can make sense of, so just dump everything. Tectonic reports errors
+                // to stderr rather than stdout.
+                let error_message = String::from_utf8_lossy(
+                    &[&latex_command.stdout[..], &latex_command.stderr[..]].concat(),
+                )
+                .into_owned();
+                eprintln!("latex error: {error_message}");
+                bail!("fail to run latex: {error_message}",);
+            }
+            return Err(self.trace_errors(indices, &lines, errors).into());
+        }
+
+        if self.config.mode == Mode::Dvi {
+            let _cst_command = Command::new("dvipdfm")
+                .args([&pdf_path])
+                .current_dir(&working_path)
+                .output()?;
+        }
+
+        let dvisvgm_command = Command::new(&self.config.dvisvgm)
+            .args(engine.dvisvgm_args(self.config.mode))
+            .args([
+                "--stdout",
+                "--relative", // Empirically reduces SVG size.
+                "--page=1-",  // Convert all pages.
+                pdf_path.to_str().unwrap(),
+            ])
+            .current_dir(&working_path)
+            .output()?;
+        if !dvisvgm_command.status.success() {
+            bail!(
+                "fail to run dvisvgm: {}",
+                String::from_utf8_lossy(&dvisvgm_command.stderr).trim()
+            );
+        }
+        // Split svgs because we might have multiple pages.
+        let svg_data = svg_utils::split_svgs(&dvisvgm_command.stdout)?;
+        let svgs = svg_data
+            .iter()
+            .map(|svg_data| svg_utils::parse_to_tree(svg_data))
+            .collect::<Result<Vec<_>, _>>()?;
+        let page_indices = svg_data
+            .iter()
+            .map(|svg_data| add_page(pages, Page::new(svg_data.to_vec())))
+            .collect::<Vec<_>>();
+
+        let bboxes = svgs
+            .iter()
+            .map(svg_utils::paths_to_bboxes)
+            .collect::<Vec<_>>();
+        let (scanner, positions) = if self.config.localization == Localization::Markers {
+            (
+                None,
+                Some(markers::read(working_path.join("source.jlpos"))?),
+            )
+        } else {
+            (Some(Scanner::new(pdf_path, &working_path)?), None)
+        };
+        let mut seen_boxes = HashSet::new();
+        let mut result = vec![];
+
+        for (&idx, line_range) in indices.iter().zip(lines) {
+            let item = &self.fragments[idx];
+            if let FragmentType::DontShow = item.ty {
+                result.push(vec![]);
+                continue;
+            }
+
+            let regions = match &positions {
+                Some(positions) => {
+                    markers::regions(positions.get(&idx).map_or(&[][..], |p| &p[..]))?
+                }
+                None => {
+                    regions_from_synctex(scanner.as_ref().unwrap(), line_range, &mut seen_boxes)
+                }
+            };
+
+            if regions.is_empty() {
+                bail!("no boxes for {}", item.src);
+            }
+            if matches!(item.ty, FragmentType::InlineMath(_)) && regions.len() > 1 {
+                bail!(
+                    "inline fragments '{}' spans multiple pages {:?} (did you disable page numbering?)",
+                    item.src,
+                    regions.keys().collect::<Vec<_>>()
+                );
+            }
+
+            let mut images = vec![];
+            for (
+                page,
+                Region {
+                    mut x_range,
+                    mut y_range,
+                    mut baseline,
+                    ..
+                },
+            ) in regions
+            {
+                let svg_idx = (page as usize)
+                    .checked_sub(1)
+                    .filter(|&idx| idx < svgs.len())
+                    .with_context(|| {
+                        format!(
+                            "{} was located on page {}, but there are {} pages",
+                            item.src,
+                            page,
+                            svgs.len()
+                        )
+                    })?;
+                // For whatever reason, the coordinate system of SVGs resulting from PDF
+                // conversion is translated.
+                let (x_base, y_base) = if self.config.mode == Mode::Pdf {
+                    let view_box = &svgs[svg_idx].svg_node().view_box.rect;
+                    (view_box.left(), view_box.top())
+                } else {
+                    (0.0, 0.0)
+                };
+                // Convert everything from TeX coordinates to SVG coordinates.
+                y_range = (
+                    y_range.0 * TEX2SVG_SCALING + y_base,
+                    y_range.1 * TEX2SVG_SCALING + y_base,
+                );
+                x_range = (
+                    x_range.0 * TEX2SVG_SCALING + x_base,
+                    x_range.1 * TEX2SVG_SCALING + x_base,
+                );
+                baseline = baseline * TEX2SVG_SCALING + y_base;
+
+                if let FragmentType::DisplayMath | FragmentType::RawBlock = item.ty {
+                    if positions.is_some() {
+                        // Markers of blocks include the blank space around them.
+                        y_range = svg_utils::fit_y_range(&bboxes[svg_idx], y_range.0, y_range.1);
+                    }
+                    y_range = svg_utils::refine_y_range(
+                        &bboxes[svg_idx],
+                        y_range.0,
+                        y_range.1,
+                        self.config.y_range_tol,
+                    );
+                }
+                y_range.0 -= self.config.y_range_margin;
+                y_range.1 += self.config.y_range_margin;
+
+                let depth = match item.ty {
+                    FragmentType::InlineMath(_) => y_range.1 - baseline,
+                    FragmentType::DisplayMath | FragmentType::RawBlock => 0.0,
+                    FragmentType::DontShow => unreachable!(),
+                };
+                images.push(Image {
+                    page: page_indices[svg_idx],
+                    view_box: (
+                        x_range.0,
+                        y_range.0,
+                        x_range.1 - x_range.0,
+                        y_range.1 - y_range.0,
+                    ),
+                    depth,
+                });
+            }
+            result.push(images);
+        }
+        Ok(result)
+    }
+}
+
+impl Collector {
+    /// Replaces the nodes of the collected fragments by their HTML, and appends the final HTML to
+    /// the document.
+    fn apply(&self, doc: &mut Pandoc, rendered: Rendered) -> Result<()> {
+        // HTML replacing the nodes of fragments, by address.
+        let mut replacements: HashMap<usize, String> = HashMap::new();
+        for (refs, html) in self.refs.iter().zip(rendered.fragments) {
+            if let Some(html) = html {
+                for &address in refs {
+                    replacements.insert(address, html.clone());
+                }
+            }
+        }
+        pandoc::replace_with_raw(doc, "html", &replacements)?;
+        doc.blocks
+            .push(Block::RawBlock("html".into(), rendered.final_html));
+        Ok(())
+    }
+
+    fn add_fragment(&mut self, ty: FragmentType, src: &str, node_ref: usize) {
+        match ty {
+            // Inline fragments are often duplicates of previous ones encountered.
+            // Caveat: if inline fragments contain expansions of macro with side effect (which is
+            // rather unlikely), then this could cause trouble!
+            FragmentType::InlineMath(ref styles) => {
+                let src = src.trim();
+                for (i, item) in self.fragments.iter().enumerate() {
+                    match item.ty {
+                        FragmentType::InlineMath(ref rstyles)
+                            if item.src == src && styles == rstyles =>
+                        {
+                            self.refs[i].push(node_ref);
+                            return;
+                        }
+                        _ => continue,
+                    }
+                }
+                self.fragments.push(Fragment {
+                    ty,
+                    src: src.into(),
+                });
+                self.refs.push(vec![node_ref]);
+            }
+            _ => {
+                self.fragments.push(Fragment {
+                    ty,
+                    src: src.trim().into(),
+                });
+                self.refs.push(vec![node_ref]);
+            }
+        }
+    }
+}
+
+impl pandoc::Visitor for Collector {
+    fn visit(&mut self, address: usize, node: pandoc::Node<'_>) -> Result<()> {
+        match node {
+            pandoc::Node::Math(Inline::Math(ty, text)) => {
+                let ty = match ty {
+                    MathType::InlineMath => FragmentType::InlineMath(
+                        self.styles.last().cloned().unwrap_or(Style::Plain),
+                    ),
+                    MathType::DisplayMath => FragmentType::display(text),
+                };
+                self.add_fragment(ty, text, address);
+            }
+            pandoc::Node::RawBlock(Block::RawBlock(format, text)) if format == "tex" => {
+                let ty = if text.trim_start().starts_with("%dontshow") {
+                    FragmentType::DontShow
+                } else {
+                    FragmentType::RawBlock
+                };
+                self.add_fragment(ty, text, address);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn enter(&mut self, container: pandoc::Container) {
+        let style = self.styles.last().cloned().unwrap_or(Style::Plain);
+        self.styles.push(match container {
+            pandoc::Container::Header(level) => style.push(StyleElement::Header(level as u64)),
+            pandoc::Container::BlockQuote => style.push(StyleElement::Quote),
+            pandoc::Container::Emph => style.push(StyleElement::Emph),
+            pandoc::Container::Strong => style.push(StyleElement::Strong),
+            pandoc::Container::Note => Style::Plain,
+        });
+    }
+
+    fn leave(&mut self) {
+        self.styles.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    /// Fragments of `tests/fixtures/pandoc/containers-*.json` common to all versions, with their
+    /// types as displayed.
+    const COMMON: [(&str, &str); 27] = [
+        ("a_1", "inline math (in header 1)"),
+        ("a_2", "inline math"),
+        (r"\int_0^1 x\,\mathrm{d}x", "display math"),
+        (
+            "%dontshow\n\\newcommand{\\R}{\\mathbb{R}}",
+            "%dontshow block",
+        ),
+        (
+            "\\begin{tikzpicture}\n\\draw (0,0) -- (1,1);\n\\end{tikzpicture}",
+            "raw block",
+        ),
+        ("a_3", "inline math"),
+        ("a_4", "inline math (in quote)"),
+        // Footnotes are typeset on their own, whatever they are in.
+        ("a_10", "inline math"),
+        ("a_5", "inline math"),
+        ("a_6", "inline math"),
+        ("a_7", "inline math"),
+        ("a_8", "inline math"),
+        ("a_9", "inline math"),
+        ("a_11", "inline math"),
+        ("a_12", "inline math"),
+        ("a_13", "inline math"),
+        ("a_14", "inline math (in emph)"),
+        ("a_15", "inline math (in emph > strong)"),
+        ("a_16", "inline math"),
+        ("a_17", "inline math"),
+        ("a_18", "inline math"),
+        ("a_19", "inline math"),
+        ("a_20", "inline math"),
+        ("a_21", "inline math"),
+        ("a_22", "inline math"),
+        ("d_1", "inline math (in header 2 > emph)"),
+        ("b_1", "inline math"),
+    ];
+
+    fn collect(version: &str) -> Collector {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(format!("tests/fixtures/pandoc/containers-{}.json", version));
+        let tree = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+        let mut doc = pandoc::read(tree).unwrap();
+        let mut collector = Collector::default();
+        pandoc::walk(&mut doc, &mut collector).unwrap();
+        collector
+    }
+
+    fn check(collector: &Collector, specific: &[&str]) {
+        let found = collector
+            .fragments
+            .iter()
+            .map(|fragment| (fragment.src.as_str(), fragment.ty.to_string()))
+            .collect::<Vec<_>>();
+        let expected = COMMON
+            .iter()
+            .copied()
+            .chain(specific.iter().map(|&src| (src, "inline math")))
+            .map(|(src, ty)| (src, ty.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, expected);
+        // Every node is collected, and the duplicates go to the same fragment.
+        let mut addresses = collector.refs.concat();
+        addresses.sort_unstable();
+        assert_eq!(addresses, (0..addresses.len()).collect::<Vec<_>>());
+        assert_eq!(collector.refs[1].len(), 2);
+    }
+
+    #[test]
+    fn collects_legacy_tables() {
+        check(&collect("1.20"), &["b_2", "b_3", "u_1", "c_1"]);
+    }
+
+    #[test]
+    fn collects_tables() {
+        check(
+            &collect("1.22"),
+            &["b_2", "b_5", "b_3", "b_4", "u_1", "c_1"],
+        );
+    }
+
+    #[test]
+    fn collects_figures() {
+        check(
+            &collect("1.23"),
+            &["b_2", "b_5", "b_3", "b_4", "u_1", "c_1", "c_2"],
+        );
+    }
+
+    #[test]
+    fn standalone_svg_holds_the_cropped_page() {
+        let config =
+            config::tests::with_env(&[], || Config::load_book(&serde_json::Value::Null)).unwrap();
+        let config = Config {
+            output: Output::Svg,
+            crop_fragments: true,
+            ..config
+        };
+        let fragments = [Fragment {
+            ty: FragmentType::InlineMath(Style::Plain),
+            src: "x".into(),
+        }];
+        let renderer = FragmentRenderer {
+            config: &config,
+            fragments: &fragments,
+        };
+        let page =
+            fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/svg/page.svg"))
+                .unwrap();
+        // Around the first glyph only, as render_pages crops it.
+        let view_box = (10.0, 15.0, 6.0, 6.0);
+        let cropped = svg_utils::crop(&svg_utils::parse_to_tree(&page).unwrap(), view_box).unwrap();
+        let pages = Pages {
+            pages: vec![Page::new(
+                cropped.to_string(&usvg::XmlOptions::default()).into_bytes(),
+            )],
+            rendered: vec![Some(vec![Image {
+                page: 0,
+                view_box,
+                depth: 1.0,
+            }])],
+            failures: vec![None],
+        };
+        let svg = renderer.standalone_svg(pages).unwrap();
+        assert!(svg.contains(r#"viewBox="10 15 6 6""#), "{}", svg);
+        assert!(svg.contains(r#"width="6.00pt" height="6.00pt""#), "{}", svg);
+        assert!(
+            svg.contains(r#"xmlns="http://www.w3.org/2000/svg""#),
+            "{}",
+            svg
+        );
+        // The glyph is there as a path, and nothing refers to definitions elsewhere.
+        assert_eq!(svg.matches("<path").count(), 1, "{}", svg);
+        assert!(
+            svg.contains(r#" d="M 1 -4 L 4 0 L 3 0 L 1 -3 Z""#),
+            "{}",
+            svg
+        );
+        assert!(!svg.contains("<use") && !svg.contains("href"), "{}", svg);
+        assert!(
+            !svg.contains("position") && !svg.contains("aria"),
+            "{}",
+            svg
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! Fragment localisation with position markers, as an alternative to SyncTeX.
+//!
+//! Each fragment is surrounded by markers that use `\pdfsavepos` to record where they end up on the
+//! page. The positions are written to `source.jlpos` when pages are shipped out, one record per
+//! line:
+//!
+//! - `<fragment>,i,<page>,<x>,<y>,<paper height>,<width>,<height>,<depth>` for inline fragments,
+//!   which are typeset in a box so that their exact dimensions are known;
+//! - `<fragment>,b,<page>,<x>,<y>,<paper height>,<line width>` and the same with `e` for the
+//!   beginning and the end of block fragments.
+//!
+//! All dimensions are in sp. Positions are measured from the bottom left corner of the page. Pages
+//! are counted from 1 as they are shipped out, with `\ReadonlyShipoutCounter` (LaTeX 2020-10 and
+//! later), so that they match the page SVGs whatever the document does with its page counter.
+//!
+//! Inline fragments are boxed in the math style they are in, with `\mathpalette`, so that the
+//! `inline_math` template decides it like it does without markers.
+
+use std::{collections::BTreeMap, collections::HashMap, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use indoc::indoc;
+
+use crate::Region;
+
+/// Macros used by the markers, inserted right after the preamble.
+pub const SETUP: &str = indoc! {r"
+    \makeatletter
+    \ifdefined\pdfsavepos\else
+      \let\pdfsavepos\savepos
+      \let\pdflastxpos\lastxpos
+      \let\pdflastypos\lastypos
+    \fi
+    \newwrite\jlpos
+    \immediate\openout\jlpos=\jobname.jlpos
+    \newsavebox\jlbox
+    \newcommand\jlmark[2]{\edef\jltmp{\noexpand\write\jlpos{#1,#2,\noexpand\the\ReadonlyShipoutCounter,\noexpand\the\pdflastxpos,\noexpand\the\pdflastypos,\number\paperheight,\number\linewidth}}\pdfsavepos\jltmp}
+    \newcommand\jlinline[2]{\def\jl@id{#1}\mathpalette\jl@inline{#2}}
+    \newcommand\jl@inline[2]{\sbox\jlbox{$\m@th#1#2$}\edef\jltmp{\noexpand\write\jlpos{\jl@id,i,\noexpand\the\ReadonlyShipoutCounter,\noexpand\the\pdflastxpos,\noexpand\the\pdflastypos,\number\paperheight,\number\wd\jlbox,\number\ht\jlbox,\number\dp\jlbox}}\pdfsavepos\jltmp\usebox\jlbox}
+    \makeatother"
+};
+
+/// Wraps the inner part of an inline fragment (inside the `inline_math` template).
+pub fn wrap_inline(fragment: usize, inner: &str) -> String {
+    format!(r"\jlinline{{{}}}{{{}}}", fragment, inner)
+}
+
+/// Wraps an expanded block fragment. The end marker goes on its own line, in case the fragment ends
+/// with a comment.
+pub fn wrap_block(fragment: usize, expanded: &str) -> String {
+    format!(
+        "\\jlmark{{{0}}}{{b}}{1}\n\\jlmark{{{0}}}{{e}}",
+        fragment, expanded
+    )
+}
+
+#[derive(Clone, Debug)]
+pub struct Position {
+    kind: char,
+    page: u32,
+    x: i64,
+    y: i64,
+    paper_height: i64,
+    /// Width, height and depth of the box for inline fragments, line width for blocks.
+    dimensions: Vec<i64>,
+}
+
+/// Reads the positions recorded by the markers, grouped by fragment.
+pub fn read(path: impl AsRef<Path>) -> Result<HashMap<usize, Vec<Position>>> {
+    let path = path.as_ref();
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("reading marker positions from {}", path.display()))?;
+    let mut positions: HashMap<usize, Vec<Position>> = HashMap::new();
+    for line in data.lines().filter(|line| !line.trim().is_empty()) {
+        let fields = line.trim().split(',').collect::<Vec<_>>();
+        let parse = |i: usize| -> Result<i64> {
+            fields
+                .get(i)
+                .context("missing field")?
+                .parse()
+                .with_context(|| format!("malformed marker record: {}", line))
+        };
+        if fields.len() < 6 || fields[1].len() != 1 {
+            bail!("malformed marker record: {}", line);
+        }
+        positions
+            .entry(parse(0)? as usize)
+            .or_default()
+            .push(Position {
+                kind: fields[1].chars().next().unwrap(),
+                page: parse(2)? as u32,
+                x: parse(3)?,
+                y: parse(4)?,
+                paper_height: parse(5)?,
+                dimensions: (6..fields.len()).map(parse).collect::<Result<_>>()?,
+            });
+    }
+    Ok(positions)
+}
+
+/// Computes the regions of a fragment from its recorded positions, in TeX coordinates (pt, from the
+/// top left corner of the page).
+///
+/// For blocks, the vertical range spans from the beginning marker to the end marker, and possibly
+/// across pages. It includes the blank space around the fragment, so it has to be fitted to the
+/// actual content afterwards.
+pub fn regions(positions: &[Position]) -> Result<BTreeMap<u32, Region>> {
+    let sp_to_pt = |x: i64| x as f64 / 65536.0;
+    let mut regions = BTreeMap::new();
+    if let Some(p) = positions.iter().find(|p| p.kind == 'i') {
+        let (width, height, depth) = match p.dimensions[..] {
+            [width, height, depth] => (width, height, depth),
+            _ => bail!("malformed inline marker"),
+        };
+        let baseline = sp_to_pt(p.paper_height - p.y);
+        regions.insert(
+            p.page,
+            Region {
+                x_range: (sp_to_pt(p.x), sp_to_pt(p.x + width)),
+                y_range: (baseline - sp_to_pt(height), baseline + sp_to_pt(depth)),
+                baseline,
+                baseline_width: sp_to_pt(width),
+            },
+        );
+        return Ok(regions);
+    }
+
+    let (begin, end) = match (
+        positions.iter().find(|p| p.kind == 'b'),
+        positions.iter().find(|p| p.kind == 'e'),
+    ) {
+        (Some(begin), Some(end)) => (begin, end),
+        _ => return Ok(regions),
+    };
+    let line_width = begin.dimensions.first().copied().unwrap_or_default();
+    for page in begin.page..=end.page {
+        let y_low = if page == begin.page {
+            begin.paper_height - begin.y
+        } else {
+            0
+        };
+        let y_high = if page == end.page {
+            end.paper_height - end.y
+        } else {
+            begin.paper_height
+        };
+        regions.insert(
+            page,
+            Region {
+                x_range: (sp_to_pt(begin.x), sp_to_pt(begin.x + line_width)),
+                y_range: (sp_to_pt(y_low), sp_to_pt(y_high)),
+                baseline: sp_to_pt(y_high),
+                baseline_width: sp_to_pt(line_width),
+            },
+        );
+    }
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn fixture() -> HashMap<usize, Vec<Position>> {
+        read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/markers/source.jlpos"))
+            .unwrap()
+    }
+
+    fn regions_of(fragment: usize) -> Vec<(u32, Region)> {
+        regions(&fixture()[&fragment])
+            .unwrap()
+            .into_iter()
+            .collect()
+    }
+
+    fn region(x_range: (f64, f64), y_range: (f64, f64), baseline: f64, width: f64) -> Region {
+        Region {
+            x_range,
+            y_range,
+            baseline,
+            baseline_width: width,
+        }
+    }
+
+    #[test]
+    fn reads_records_by_fragment() {
+        let positions = fixture();
+        assert_eq!(positions.len(), 3);
+        let kinds = |fragment: usize| {
+            positions[&fragment]
+                .iter()
+                .map(|p| p.kind)
+                .collect::<String>()
+        };
+        assert_eq!(kinds(0), "i");
+        assert_eq!(kinds(1), "be");
+        assert_eq!(kinds(2), "be");
+        assert_eq!(positions[&1][1].page, 2);
+    }
+
+    #[test]
+    fn inline_region_is_the_box() {
+        assert_eq!(
+            regions_of(0),
+            [(1, region((100.0, 120.0), (93.0, 102.0), 100.0, 20.0))]
+        );
+    }
+
+    #[test]
+    fn block_regions_span_pages() {
+        assert_eq!(
+            regions_of(1),
+            [
+                (1, region((0.0, 300.0), (200.0, 16000.0), 16000.0, 300.0)),
+                (2, region((0.0, 300.0), (0.0, 50.0), 50.0, 300.0)),
+            ]
+        );
+        assert_eq!(
+            regions_of(2),
+            [(2, region((0.0, 300.0), (100.0, 150.0), 150.0, 300.0))]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        for record in ["0,i,1,2", "0,in,1,2,3,4", "0,i,1,x,3,4"] {
+            let mut file = NamedTempFile::new().unwrap();
+            writeln!(file, "{}", record).unwrap();
+            assert!(read(file.path()).is_err(), "{}", record);
+        }
+        // Inline records need the three dimensions of the box.
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "0,i,1,2,3,4,5").unwrap();
+        assert!(regions(&read(file.path()).unwrap()[&0]).is_err());
+    }
+}
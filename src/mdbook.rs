@@ -0,0 +1,322 @@
+//! The mdBook preprocessor front end.
+//!
+//! mdBook runs preprocessors with a JSON array `[context, book]` on stdin and expects the book back
+//! on stdout. Configuration comes from the `[preprocessor.just-latex]` table of `book.toml`, which
+//! is part of the context. Each chapter is rendered on its own, as it becomes a page of its own:
+//! `$...$` and `$$...$$` in its Markdown are replaced by HTML, and the final HTML (e.g. the
+//! decompressor script) is appended to it.
+//!
+//! Note that chapters live in different folders of the rendered book, so with the "external"
+//! output, `asset_url_prefix` should be an absolute URL.
+
+use std::ops::Range;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::{Collector, FragmentType, Style, StyleElement};
+
+/// Renders the math in all chapters of a book, given the context of the preprocessor.
+pub fn preprocess(context: &Value, book: &mut Value) -> Result<()> {
+    let config = Config::load_book(&context["config"]["preprocessor"]["just-latex"])?;
+    config.sanity_check()?;
+    let sections = book["sections"]
+        .as_array_mut()
+        .context("reading sections of the book")?;
+    preprocess_items(&config, sections)
+}
+
+fn preprocess_items(config: &Config, items: &mut [Value]) -> Result<()> {
+    for item in items {
+        // Separators and part titles are plain strings or hold one.
+        let chapter = match item.get_mut("Chapter") {
+            Some(chapter) => chapter,
+            None => continue,
+        };
+        let name = chapter["name"].as_str().unwrap_or_default().to_string();
+        let content = chapter["content"]
+            .as_str()
+            .context("reading chapter content")?;
+        let content = render_chapter(config, content)
+            .with_context(|| format!("rendering chapter {}", name))?;
+        chapter["content"] = Value::String(content);
+        if let Some(sub_items) = chapter["sub_items"].as_array_mut() {
+            preprocess_items(config, sub_items)?;
+        }
+    }
+    Ok(())
+}
+
+/// Renders the math in the Markdown of a chapter.
+fn render_chapter(config: &Config, content: &str) -> Result<String> {
+    let mut collector = Collector::default();
+    let ranges = find_math(content, &mut collector);
+    if ranges.is_empty() {
+        return Ok(content.into());
+    }
+    let rendered = crate::render(config, &collector.fragments)?;
+    // HTML replacing each piece of math, by its index in `ranges`.
+    let mut htmls = vec![None; ranges.len()];
+    for (refs, html) in collector.refs.iter().zip(&rendered.fragments) {
+        for &index in refs {
+            htmls[index] = html.as_deref();
+        }
+    }
+    let mut output = String::with_capacity(content.len());
+    let mut last = 0;
+    for (range, html) in ranges.into_iter().zip(htmls) {
+        output.push_str(&content[last..range.start]);
+        match html {
+            // Markdown blocks such as headers and table rows end at line breaks.
+            Some(html) => output.push_str(&html.replace('\n', " ")),
+            // Fallen back on: leave the math for MathJax or whatever the book uses.
+            None => output.push_str(&content[range.clone()]),
+        }
+        last = range.end;
+    }
+    output.push_str(&content[last..]);
+    if !rendered.final_html.is_empty() {
+        output.push_str("\n\n");
+        output.push_str(&rendered.final_html);
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+/// Finds `$...$` and `$$...$$` in Markdown, outside code, adding them to `collector`. Returns their
+/// ranges; the address of each fragment in the collector is its index in them.
+///
+/// Like in Pandoc, an opening `$` must be followed by a non-space character, and a closing `$` must
+/// follow a non-space character and not be followed by a digit. `\$` is a literal dollar sign.
+///
+/// Code is either fenced, or indented by four columns after a blank line. In list items, indented
+/// lines are taken as the content of the item rather than code, since that depends on the width of
+/// the list marker.
+fn find_math(content: &str, collector: &mut Collector) -> Vec<Range<usize>> {
+    let bytes = content.as_bytes();
+    let mut ranges = vec![];
+    let mut i = 0;
+    let mut at_line_start = true;
+    // The character and length of the fence of the current code block.
+    let mut fence: Option<(u8, usize)> = None;
+    let mut prev_blank = true;
+    let mut in_indented_code = false;
+    let mut in_list = false;
+    let mut style = Style::Plain;
+    while i < bytes.len() {
+        if at_line_start {
+            let line_end = content[i..].find('\n').map_or(bytes.len(), |n| i + n + 1);
+            let line = content[i..line_end].trim_end();
+            let rest = line.trim_start_matches([' ', '\t']);
+            let indent = indentation(line);
+            let indented = indent >= 4;
+            let blank = rest.is_empty();
+            if !blank && indent == 0 && prev_blank {
+                in_list = false;
+            }
+            if !blank && !indented && is_list_item(rest) {
+                in_list = true;
+            }
+            in_indented_code = !blank
+                && indented
+                && fence.is_none()
+                && !in_list
+                && (prev_blank || in_indented_code);
+            prev_blank = blank;
+            if in_indented_code {
+                i = line_end;
+                continue;
+            }
+            match fence {
+                Some((c, n)) => {
+                    if !indented
+                        && fence_length(rest, c) >= n
+                        && rest.trim_start_matches(c as char).is_empty()
+                    {
+                        fence = None;
+                    }
+                    i = line_end;
+                    continue;
+                }
+                None if !indented => {
+                    for c in [b'`', b'~'] {
+                        let n = fence_length(rest, c);
+                        if n >= 3 {
+                            fence = Some((c, n));
+                        }
+                    }
+                    if fence.is_some() {
+                        i = line_end;
+                        continue;
+                    }
+                }
+                None => {}
+            }
+            style = line_style(rest);
+            at_line_start = false;
+        }
+        match bytes[i] {
+            b'\n' => {
+                at_line_start = true;
+                i += 1;
+            }
+            b'\\' if bytes.get(i + 1).is_some_and(|&c| c != b'\n') => i += 2,
+            b'`' => {
+                let n = bytes[i..].iter().take_while(|&&c| c == b'`').count();
+                i += n;
+                if let Some(end) = find_code_span_end(content, i, n) {
+                    i = end;
+                }
+            }
+            b'$' if bytes.get(i + 1) == Some(&b'$') => match find_closing(content, i + 2, "$$") {
+                Some(end) => {
+                    let src = &content[i + 2..end];
+                    collector.add_fragment(FragmentType::display(src), src, ranges.len());
+                    ranges.push(i..end + 2);
+                    i = end + 2;
+                }
+                None => i += 2,
+            },
+            b'$' => match find_inline_closing(content, i + 1) {
+                Some(end) => {
+                    let ty = FragmentType::InlineMath(style.clone());
+                    collector.add_fragment(ty, &content[i + 1..end], ranges.len());
+                    ranges.push(i..end + 1);
+                    i = end + 1;
+                }
+                None => i += 1,
+            },
+            _ => i += 1,
+        }
+    }
+    ranges
+}
+
+/// Width of the indentation of a line, with tabs stopping every four columns.
+fn indentation(line: &str) -> usize {
+    let mut width = 0;
+    for c in line.chars() {
+        match c {
+            ' ' => width += 1,
+            '\t' => width += 4 - width % 4,
+            _ => break,
+        }
+    }
+    width
+}
+
+/// Whether a line, without its indentation, starts a list item: `-`, `*`, `+` or a number followed
+/// by `.` or `)`, then a space or the end of the line.
+fn is_list_item(line: &str) -> bool {
+    let digits = line.bytes().take_while(u8::is_ascii_digit).count();
+    let rest = match digits {
+        0 => line.strip_prefix(['-', '*', '+']),
+        1..=9 => line[digits..].strip_prefix(['.', ')']),
+        _ => None,
+    };
+    rest.is_some_and(|rest| rest.is_empty() || rest.starts_with([' ', '\t']))
+}
+
+/// Number of `c` a line starts with.
+fn fence_length(line: &str, c: u8) -> usize {
+    line.bytes().take_while(|&b| b == c).count()
+}
+
+/// Style of inline math in a line, from the block quote markers and header it starts with.
+fn line_style(mut line: &str) -> Style {
+    let mut style = Style::Plain;
+    while let Some(rest) = line.strip_prefix('>') {
+        style = style.push(StyleElement::Quote);
+        line = rest.trim_start();
+    }
+    let level = fence_length(line, b'#');
+    if (1..=6).contains(&level) && line[level..].starts_with(' ') {
+        style = style.push(StyleElement::Header(level as u64));
+    }
+    style
+}
+
+/// Finds the end of a code span opened by `n` backticks before `start`, i.e. after the next run of
+/// exactly `n` backticks. Code spans do not cross paragraphs.
+fn find_code_span_end(content: &str, start: usize, n: usize) -> Option<usize> {
+    let bytes = content.as_bytes();
+    let mut i = start;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(b"\n\n") {
+            return None;
+        }
+        if bytes[i] == b'`' {
+            let run = bytes[i..].iter().take_while(|&&c| c == b'`').count();
+            if run == n {
+                return Some(i + n);
+            }
+            i += run;
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Finds the next unescaped `delimiter` from `start`, within the paragraph.
+fn find_closing(content: &str, start: usize, delimiter: &str) -> Option<usize> {
+    let bytes = content.as_bytes();
+    let mut i = start;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(b"\n\n") {
+            return None;
+        }
+        if bytes[i] == b'\\' {
+            i += 2;
+        } else if bytes[i..].starts_with(delimiter.as_bytes()) {
+            return Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Finds the closing `$` of inline math opened right before `start`.
+fn find_inline_closing(content: &str, start: usize) -> Option<usize> {
+    let bytes = content.as_bytes();
+    match bytes.get(start) {
+        Some(c) if !c.is_ascii_whitespace() => {}
+        _ => return None,
+    }
+    let mut i = start;
+    while let Some(end) = find_closing(content, i, "$") {
+        let after = bytes.get(end + 1);
+        if !bytes[end - 1].is_ascii_whitespace() && !after.is_some_and(u8::is_ascii_digit) {
+            return Some(end);
+        }
+        i = end + 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn math(content: &str) -> Vec<&str> {
+        find_math(content, &mut Collector::default())
+            .into_iter()
+            .map(|range| &content[range])
+            .collect()
+    }
+
+    #[test]
+    fn skips_code() {
+        let content = "$a$ and `$b$`\n\n```\n$c$\n```\n\n    $d$\n\n\t$e$\n    $f$\n$g$\n";
+        assert_eq!(math(content), ["$a$", "$g$"]);
+    }
+
+    #[test]
+    fn indented_lines_continue_paragraphs_and_list_items() {
+        let content =
+            "Text\n    $a$\n\n- Item\n\n    $b$\n\n1. Item\n\n       $c$\n\nText\n\n    $d$\n";
+        assert_eq!(math(content), ["$a$", "$b$", "$c$"]);
+    }
+}
@@ -0,0 +1,530 @@
+//! A typed model of the Pandoc AST, and a walker over it.
+//!
+//! Only the parts JustLaTeX cares about are typed in detail. Alignments, list styles and the like
+//! are kept as they are. Either way, documents come out byte for byte the way Pandoc wrote them:
+//! numbers keep their text (e.g. `1.0e-2`), thanks to the `arbitrary_precision` feature of
+//! serde_json.
+//!
+//! Tables are laid out differently depending on the `pandoc-api-version` (see
+//! [`crate::pandoc_api`]). Either layout is read, and [`walk`] rejects those that do not match the
+//! version, like nodes that do not exist in it.
+//!
+//! Nodes holding fragments (`Math` inlines and `RawBlock` blocks) are identified by their
+//! address: their index among all such nodes, in the order [`walk`] visits them. Fragments are
+//! collected in a first walk, and their nodes replaced in a second one.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Context, Result};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::{Number, Value};
+
+use crate::pandoc_api::ApiVersion;
+
+/// Reads a document, after checking that the `pandoc-api-version` it declares is supported.
+pub fn read(tree: Value) -> Result<Pandoc> {
+    let api = ApiVersion::detect(&tree)?;
+    serde_json::from_value(tree)
+        .with_context(|| format!("reading the document as pandoc-api-version {}", api))
+}
+
+/// A whole document.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pandoc {
+    #[serde(rename = "pandoc-api-version")]
+    pub api_version: Vec<u64>,
+    pub meta: BTreeMap<String, MetaValue>,
+    pub blocks: Vec<Block>,
+}
+
+// Variant names follow the constructors of pandoc-types.
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "t", content = "c")]
+pub enum MetaValue {
+    MetaMap(BTreeMap<String, MetaValue>),
+    MetaList(Vec<MetaValue>),
+    MetaBool(bool),
+    MetaString(String),
+    MetaInlines(Vec<Inline>),
+    MetaBlocks(Vec<Block>),
+}
+
+/// Identifier, classes and key-value pairs.
+pub type Attr = (String, Vec<String>, Vec<(String, String)>);
+/// Short caption, if any, and the caption proper.
+pub type Caption = (Option<Vec<Inline>>, Vec<Block>);
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "t", content = "c")]
+pub enum Block {
+    Plain(Vec<Inline>),
+    Para(Vec<Inline>),
+    LineBlock(Vec<Vec<Inline>>),
+    CodeBlock(Attr, String),
+    RawBlock(String, String),
+    BlockQuote(Vec<Block>),
+    OrderedList(Value, Vec<Vec<Block>>),
+    BulletList(Vec<Vec<Block>>),
+    DefinitionList(Vec<(Vec<Inline>, Vec<Vec<Block>>)>),
+    Header(i64, Attr, Vec<Inline>),
+    HorizontalRule,
+    Table(Box<Table>),
+    Figure(Attr, Caption, Vec<Block>),
+    Div(Attr, Vec<Block>),
+    Null,
+}
+
+/// The two table models of the Pandoc API (see [`crate::pandoc_api`]), told apart by their number
+/// of fields.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Table {
+    /// Attributes, caption, column specs, head, bodies and foot.
+    Current(
+        Attr,
+        Caption,
+        Vec<(Value, ColWidth)>,
+        TableHead,
+        Vec<TableBody>,
+        TableFoot,
+    ),
+    /// Caption, alignments, widths, header cells and rows of cells, each cell being blocks.
+    Legacy(
+        Vec<Inline>,
+        Vec<Value>,
+        Vec<Value>,
+        Vec<Vec<Block>>,
+        Vec<Vec<Vec<Block>>>,
+    ),
+}
+
+impl<'de> Deserialize<'de> for Table {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fields = Vec::<Value>::deserialize(deserializer)?;
+        let table = match fields.len() {
+            5 => serde_json::from_value(Value::Array(fields)).map(
+                |(caption, alignments, widths, header, rows)| {
+                    Table::Legacy(caption, alignments, widths, header, rows)
+                },
+            ),
+            6 => serde_json::from_value(Value::Array(fields)).map(
+                |(attr, caption, specs, head, bodies, foot)| {
+                    Table::Current(attr, caption, specs, head, bodies, foot)
+                },
+            ),
+            n => return Err(de::Error::invalid_length(n, &"a Table of 5 or 6 fields")),
+        };
+        table.map_err(|e| de::Error::custom(format!("malformed Table: {}", e)))
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "t", content = "c")]
+pub enum ColWidth {
+    /// The fraction of the width of the text, kept as Pandoc wrote it (e.g. `1.0e-2`).
+    ColWidth(Number),
+    ColWidthDefault,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TableHead(pub Attr, pub Vec<Row>);
+
+/// Attributes, number of row head columns, head rows and body rows.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TableBody(pub Attr, pub i64, pub Vec<Row>, pub Vec<Row>);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TableFoot(pub Attr, pub Vec<Row>);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Row(pub Attr, pub Vec<Cell>);
+
+/// Attributes, alignment, row span, column span and content.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Cell(pub Attr, pub Value, pub i64, pub i64, pub Vec<Block>);
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "t", content = "c")]
+pub enum Inline {
+    Str(String),
+    Emph(Vec<Inline>),
+    Underline(Vec<Inline>),
+    Strong(Vec<Inline>),
+    Strikeout(Vec<Inline>),
+    Superscript(Vec<Inline>),
+    Subscript(Vec<Inline>),
+    SmallCaps(Vec<Inline>),
+    Quoted(Value, Vec<Inline>),
+    Cite(Vec<Citation>, Vec<Inline>),
+    Code(Attr, String),
+    Space,
+    SoftBreak,
+    LineBreak,
+    Math(MathType, String),
+    RawInline(String, String),
+    Link(Attr, Vec<Inline>, (String, String)),
+    Image(Attr, Vec<Inline>, (String, String)),
+    Note(Vec<Block>),
+    Span(Attr, Vec<Inline>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "t")]
+pub enum MathType {
+    InlineMath,
+    DisplayMath,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Citation {
+    pub citation_id: String,
+    pub citation_prefix: Vec<Inline>,
+    pub citation_suffix: Vec<Inline>,
+    pub citation_mode: Value,
+    pub citation_note_num: i64,
+    pub citation_hash: i64,
+}
+
+/// Containers changing how fragments inside them are typeset.
+#[derive(Clone, Copy, Debug)]
+pub enum Container {
+    Header(i64),
+    BlockQuote,
+    Emph,
+    Strong,
+    /// Footnotes, typeset away from where they are referenced.
+    Note,
+}
+
+/// A node that may hold a fragment.
+pub enum Node<'n> {
+    Math(&'n mut Inline),
+    RawBlock(&'n mut Block),
+}
+
+pub trait Visitor {
+    /// Called on every `Math` inline and `RawBlock` block, along with its address.
+    fn visit(&mut self, address: usize, node: Node<'_>) -> Result<()>;
+
+    /// Called when entering a container, before its content is visited.
+    fn enter(&mut self, _container: Container) {}
+
+    /// Called when leaving the last container entered.
+    fn leave(&mut self) {}
+}
+
+/// Replaces the nodes at the given addresses by raw content in `format`: `Math` inlines become
+/// `RawInline`s and `RawBlock`s stay `RawBlock`s.
+pub fn replace_with_raw(
+    doc: &mut Pandoc,
+    format: &str,
+    replacements: &HashMap<usize, String>,
+) -> Result<()> {
+    struct Replacer<'r> {
+        format: &'r str,
+        replacements: &'r HashMap<usize, String>,
+    }
+
+    impl Visitor for Replacer<'_> {
+        fn visit(&mut self, address: usize, node: Node<'_>) -> Result<()> {
+            if let Some(raw) = self.replacements.get(&address) {
+                match node {
+                    Node::Math(inline) => {
+                        *inline = Inline::RawInline(self.format.into(), raw.clone())
+                    }
+                    Node::RawBlock(block) => {
+                        *block = Block::RawBlock(self.format.into(), raw.clone())
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    walk(
+        doc,
+        &mut Replacer {
+            format,
+            replacements,
+        },
+    )
+}
+
+/// Visits the nodes of a document that may hold fragments, in document order.
+pub fn walk(doc: &mut Pandoc, visitor: &mut impl Visitor) -> Result<()> {
+    Walker {
+        visitor,
+        api: ApiVersion::new(&doc.api_version)?,
+        address: 0,
+    }
+    .blocks(&mut doc.blocks)
+}
+
+struct Walker<'v, V> {
+    visitor: &'v mut V,
+    api: ApiVersion,
+    address: usize,
+}
+
+impl<'v, V: Visitor> Walker<'v, V> {
+    fn visit(&mut self, node: Node<'_>) -> Result<()> {
+        let address = self.address;
+        self.address += 1;
+        self.visitor.visit(address, node)
+    }
+
+    fn within(
+        &mut self,
+        container: Container,
+        f: impl FnOnce(&mut Self) -> Result<()>,
+    ) -> Result<()> {
+        self.visitor.enter(container);
+        let result = f(self);
+        self.visitor.leave();
+        result
+    }
+
+    fn blocks(&mut self, blocks: &mut [Block]) -> Result<()> {
+        blocks.iter_mut().try_for_each(|block| self.block(block))
+    }
+
+    fn inlines(&mut self, inlines: &mut [Inline]) -> Result<()> {
+        inlines
+            .iter_mut()
+            .try_for_each(|inline| self.inline(inline))
+    }
+
+    fn block(&mut self, block: &mut Block) -> Result<()> {
+        match block {
+            Block::RawBlock(..) => self.visit(Node::RawBlock(block)),
+            Block::Plain(inlines) | Block::Para(inlines) => self.inlines(inlines),
+            Block::LineBlock(lines) => lines.iter_mut().try_for_each(|line| self.inlines(line)),
+            Block::BlockQuote(blocks) => {
+                self.within(Container::BlockQuote, |walker| walker.blocks(blocks))
+            }
+            Block::OrderedList(_, items) | Block::BulletList(items) => {
+                items.iter_mut().try_for_each(|item| self.blocks(item))
+            }
+            Block::DefinitionList(items) => {
+                for (term, definitions) in items {
+                    self.inlines(term)?;
+                    definitions
+                        .iter_mut()
+                        .try_for_each(|definition| self.blocks(definition))?;
+                }
+                Ok(())
+            }
+            Block::Header(level, _, inlines) => {
+                self.within(Container::Header(*level), |walker| walker.inlines(inlines))
+            }
+            Block::Table(table) => self.table(table),
+            Block::Figure(_, caption, blocks) => {
+                if self.api < ApiVersion::V1_23 {
+                    bail!("Figure does not exist in pandoc-api-version {}", self.api);
+                }
+                self.caption(caption)?;
+                self.blocks(blocks)
+            }
+            Block::Div(_, blocks) => self.blocks(blocks),
+            Block::CodeBlock(..) | Block::HorizontalRule | Block::Null => Ok(()),
+        }
+    }
+
+    fn inline(&mut self, inline: &mut Inline) -> Result<()> {
+        match inline {
+            Inline::Math(..) => self.visit(Node::Math(inline)),
+            Inline::Emph(inlines) => self.within(Container::Emph, |walker| walker.inlines(inlines)),
+            Inline::Strong(inlines) => {
+                self.within(Container::Strong, |walker| walker.inlines(inlines))
+            }
+            Inline::Underline(inlines)
+            | Inline::Strikeout(inlines)
+            | Inline::Superscript(inlines)
+            | Inline::Subscript(inlines)
+            | Inline::SmallCaps(inlines)
+            | Inline::Quoted(_, inlines)
+            | Inline::Link(_, inlines, _)
+            | Inline::Image(_, inlines, _)
+            | Inline::Span(_, inlines) => self.inlines(inlines),
+            Inline::Cite(citations, inlines) => {
+                for citation in citations {
+                    self.inlines(&mut citation.citation_prefix)?;
+                    self.inlines(&mut citation.citation_suffix)?;
+                }
+                self.inlines(inlines)
+            }
+            Inline::Note(blocks) => self.within(Container::Note, |walker| walker.blocks(blocks)),
+            Inline::Str(_)
+            | Inline::Code(..)
+            | Inline::Space
+            | Inline::SoftBreak
+            | Inline::LineBreak
+            | Inline::RawInline(..) => Ok(()),
+        }
+    }
+
+    fn caption(&mut self, (short, long): &mut Caption) -> Result<()> {
+        if let Some(short) = short {
+            self.inlines(short)?;
+        }
+        self.blocks(long)
+    }
+
+    fn rows(&mut self, rows: &mut [Row]) -> Result<()> {
+        for Row(_, cells) in rows {
+            for Cell(_, _, _, _, blocks) in cells {
+                self.blocks(blocks)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn table(&mut self, table: &mut Table) -> Result<()> {
+        let legacy = matches!(table, Table::Legacy(..));
+        if legacy != (self.api < ApiVersion::V1_21) {
+            bail!(
+                "Table has the layout of pandoc-api-version {} instead of {}",
+                if legacy {
+                    ApiVersion::V1_17
+                } else {
+                    ApiVersion::V1_21
+                },
+                self.api
+            );
+        }
+        match table {
+            Table::Current(_, caption, _, TableHead(_, head), bodies, TableFoot(_, foot)) => {
+                self.caption(caption)?;
+                self.rows(head)?;
+                for TableBody(_, _, head, body) in bodies {
+                    self.rows(head)?;
+                    self.rows(body)?;
+                }
+                self.rows(foot)
+            }
+            Table::Legacy(caption, _, _, header, rows) => {
+                self.inlines(caption)?;
+                header.iter_mut().try_for_each(|cell| self.blocks(cell))?;
+                for row in rows {
+                    row.iter_mut().try_for_each(|cell| self.blocks(cell))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    /// A document of `tests/fixtures/pandoc`, as pandoc of the given API version writes it.
+    fn fixture_data(version: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(format!("tests/fixtures/pandoc/containers-{}.json", version));
+        let mut data = fs::read(path).unwrap();
+        assert_eq!(data.pop(), Some(b'\n'));
+        data
+    }
+
+    fn fixture(version: &str) -> Value {
+        serde_json::from_slice(&fixture_data(version)).unwrap()
+    }
+
+    fn round_trip(version: &str) {
+        let data = fixture_data(version);
+        let doc = read(serde_json::from_slice(&data).unwrap()).unwrap();
+        assert_eq!(
+            String::from_utf8(serde_json::to_vec(&doc).unwrap()).unwrap(),
+            String::from_utf8(data).unwrap()
+        );
+    }
+
+    /// Walks a document without visiting anything, to check it.
+    fn check(doc: &mut Pandoc) -> Result<()> {
+        struct Ignore;
+        impl Visitor for Ignore {
+            fn visit(&mut self, _: usize, _: Node<'_>) -> Result<()> {
+                Ok(())
+            }
+        }
+        walk(doc, &mut Ignore)
+    }
+
+    #[test]
+    fn round_trips_1_20() {
+        round_trip("1.20");
+    }
+
+    #[test]
+    fn round_trips_1_22() {
+        round_trip("1.22");
+    }
+
+    #[test]
+    fn round_trips_1_23() {
+        round_trip("1.23");
+    }
+
+    #[test]
+    fn deserializes_without_read() {
+        for version in ["1.20", "1.22", "1.23"] {
+            let data = fixture_data(version);
+            let from_value: Pandoc = serde_json::from_value(fixture(version)).unwrap();
+            let from_slice: Pandoc = serde_json::from_slice(&data).unwrap();
+            assert_eq!(serde_json::to_vec(&from_value).unwrap(), data);
+            assert_eq!(serde_json::to_vec(&from_slice).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn rejects_tables_of_another_version() {
+        let mut tree = fixture("1.22");
+        tree["pandoc-api-version"] = serde_json::json!([1, 20]);
+        let error = check(&mut read(tree).unwrap()).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Table has the layout of pandoc-api-version 1.21-1.22 instead of 1.17-1.20"
+        );
+        let mut tree = fixture("1.20");
+        tree["pandoc-api-version"] = serde_json::json!([1, 23]);
+        let error = check(&mut read(tree).unwrap()).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Table has the layout of pandoc-api-version 1.17-1.20 instead of 1.23"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_tables() {
+        let table = serde_json::json!({ "t": "Table", "c": [[], [], []] });
+        let error = serde_json::from_value::<Block>(table)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("a Table of 5 or 6 fields"), "{}", error);
+        let table = serde_json::json!({ "t": "Table", "c": [[], [], [], [], [], 1] });
+        let error = serde_json::from_value::<Block>(table)
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("malformed Table"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_figures_before_1_23() {
+        let mut tree = fixture("1.23");
+        tree["pandoc-api-version"] = serde_json::json!([1, 22, 2, 1]);
+        let error = check(&mut read(tree).unwrap()).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Figure does not exist in pandoc-api-version 1.21-1.22"
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Versions of the Pandoc API (i.e. of the pandoc-types package) that the filter understands.
+//!
+//! The JSON AST changes layout every now and then, so the `pandoc-api-version` a document declares
+//! decides which nodes of the model of [`crate::pandoc`] it may hold (see [`crate::pandoc::walk`]):
+//!
+//! - 1.17 to 1.20 (pandoc 1.18 to 2.9) have the old table model, where a table is a caption,
+//!   alignments, widths, a header row and rows of cells;
+//! - 1.21 and 1.22 (pandoc 2.10 to 2.19) have the current table model, with attributes, captions
+//!   made of blocks, heads, bodies and feet;
+//! - 1.23 (pandoc 3) adds figures, which are an error in older documents.
+//!
+//! Older documents do not declare a version at all, and newer ones may contain nodes we do not
+//! know, so both are rejected.
+
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ApiVersion {
+    /// 1.17 to 1.20, with the old table model.
+    V1_17,
+    /// 1.21 and 1.22, with the current table model.
+    V1_21,
+    /// 1.23, with figures.
+    V1_23,
+}
+
+impl ApiVersion {
+    /// Reads and validates the version declared by a document.
+    pub fn detect(tree: &Value) -> Result<Self> {
+        let version = match tree.get("pandoc-api-version") {
+            Some(version) => version
+                .as_array()
+                .context("reading pandoc-api-version")?
+                .iter()
+                .map(|n| n.as_u64().context("reading pandoc-api-version"))
+                .collect::<Result<Vec<_>>>()?,
+            None => bail!(
+                "the document does not declare a pandoc-api-version; pandoc 1.18 or newer is \
+                 required"
+            ),
+        };
+        Self::new(&version)
+    }
+
+    /// Validates a version, given as its components.
+    pub fn new(version: &[u64]) -> Result<Self> {
+        let declared = version
+            .iter()
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        Ok(match *version {
+            [1, 17..=20, ..] => ApiVersion::V1_17,
+            [1, 21..=22, ..] => ApiVersion::V1_21,
+            [1, 23, ..] => ApiVersion::V1_23,
+            _ => bail!(
+                "unsupported pandoc-api-version {}: only 1.17 to 1.23 (pandoc 1.18 to 3.x) are \
+                 supported",
+                declared
+            ),
+        })
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiVersion::V1_17 => write!(f, "1.17-1.20"),
+            ApiVersion::V1_21 => write!(f, "1.21-1.22"),
+            ApiVersion::V1_23 => write!(f, "1.23"),
+        }
+    }
+}
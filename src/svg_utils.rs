@@ -2,6 +2,8 @@ use anyhow::{bail, Result};
 use regex::Regex;
 use usvg::{NodeExt, PathBbox};
 
+use crate::config::ColorRule;
+
 /// Splits a stream of multiple SVGs (returned by dvisvgm).
 pub fn split_svgs(bytes: &[u8]) -> Result<Vec<&[u8]>> {
     let mut reader = quick_xml::Reader::from_bytes(bytes);
@@ -19,6 +21,34 @@ pub fn split_svgs(bytes: &[u8]) -> Result<Vec<&[u8]>> {
     Ok(cuts.windows(2).map(|w| &bytes[w[0]..w[1]]).collect())
 }
 
+/// Reads the viewBox of an SVG (x, y, width and height) without parsing the whole tree.
+pub fn view_box(svg_data: &[u8]) -> Result<(f64, f64, f64, f64)> {
+    let mut reader = quick_xml::Reader::from_bytes(svg_data);
+    loop {
+        match reader.read_event_unbuffered()? {
+            quick_xml::events::Event::Start(e) | quick_xml::events::Event::Empty(e)
+                if e.name() == b"svg" =>
+            {
+                let attr = match e.try_get_attribute("viewBox")? {
+                    Some(attr) => attr,
+                    None => bail!("SVG has no viewBox"),
+                };
+                let numbers = String::from_utf8_lossy(&attr.value)
+                    .split(|c: char| c.is_whitespace() || c == ',')
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse::<f64>())
+                    .collect::<Result<Vec<_>, _>>()?;
+                return match numbers[..] {
+                    [x, y, width, height] => Ok((x, y, width, height)),
+                    _ => bail!("malformed viewBox in SVG"),
+                };
+            }
+            quick_xml::events::Event::Eof => bail!("SVG has no <svg> element"),
+            _ => {}
+        }
+    }
+}
+
 /// Finds paths and images in an SVG and computes their bboxes.
 pub fn paths_to_bboxes(tree: &usvg::Tree) -> Vec<PathBbox> {
     tree.root()
@@ -28,6 +58,252 @@ pub fn paths_to_bboxes(tree: &usvg::Tree) -> Vec<PathBbox> {
         .collect()
 }
 
+/// Builds a standalone SVG showing only the part of `tree` inside `view_box` (x, y, width and
+/// height, in user units).
+///
+/// Paths and images intersecting the region are copied over with their absolute transforms, so the
+/// groups they were in are dropped. Definitions are kept as they are, since paths may refer to them.
+pub fn crop(tree: &usvg::Tree, view_box: (f64, f64, f64, f64)) -> Result<usvg::Tree> {
+    let (x, y, width, height) = view_box;
+    let rect = match usvg::Rect::new(x, y, width, height) {
+        Some(rect) => rect,
+        None => bail!("empty region to crop"),
+    };
+    let size = usvg::Size::new(width, height).unwrap();
+    let cropped = usvg::Tree::create(usvg::Svg {
+        size,
+        view_box: usvg::ViewBox {
+            rect,
+            aspect: usvg::AspectRatio::default(),
+        },
+    });
+    for mut def in tree.defs().children() {
+        cropped.defs().append(def.make_deep_copy());
+    }
+
+    // The first child of the root holds the definitions.
+    for node in tree.root().children().skip(1) {
+        for mut node in node.descendants().filter(|node| !node.has_children()) {
+            let intersects = node.calculate_bbox().is_some_and(|bbox| {
+                bbox.right() >= x
+                    && bbox.left() <= x + width
+                    && bbox.bottom() >= y
+                    && bbox.top() <= y + height
+            });
+            if !intersects {
+                continue;
+            }
+            let transform = node.abs_transform();
+            let mut copy = node.make_deep_copy();
+            match &mut *copy.borrow_mut() {
+                usvg::NodeKind::Path(path) => path.transform = transform,
+                usvg::NodeKind::Image(image) => image.transform = transform,
+                _ => continue,
+            }
+            cropped.root().append(copy);
+        }
+    }
+    Ok(cropped)
+}
+
+/// Turns an SVG into markup that can be inlined in an HTML document: the content of the root
+/// element, with black fills and strokes changed to `currentColor` so that it follows the color of
+/// the surrounding text, and ids prefixed with `namespace` so that they do not clash with those of
+/// other SVGs in the same document.
+pub fn inline_markup(svg_data: &[u8], namespace: &str) -> Result<String> {
+    let mut reader = quick_xml::Reader::from_bytes(svg_data);
+    let mut writer = quick_xml::Writer::new(std::io::Cursor::new(vec![]));
+    let is_black = |value: &str| {
+        ["#000", "#000000", "black", "rgb(0,0,0)"].contains(&value.trim().to_lowercase().as_str())
+    };
+    let rewrite = |e: &quick_xml::events::BytesStart| -> Result<quick_xml::events::BytesStart> {
+        let mut new_e = quick_xml::events::BytesStart::owned_name(e.name().to_vec());
+        for attr in e.attributes() {
+            let attr = attr?;
+            let value = String::from_utf8_lossy(&attr.unescaped_value()?).into_owned();
+            let value = match attr.key {
+                b"id" => format!("{}-{}", namespace, value),
+                b"href" | b"xlink:href" if value.starts_with('#') => {
+                    format!("#{}-{}", namespace, &value[1..])
+                }
+                b"fill" | b"stroke" if is_black(&value) => "currentColor".into(),
+                _ => value.replace("url(#", &format!("url(#{}-", namespace)),
+            };
+            new_e.push_attribute((&*String::from_utf8_lossy(attr.key), value.as_str()));
+        }
+        Ok(new_e)
+    };
+    loop {
+        match reader.read_event_unbuffered()? {
+            quick_xml::events::Event::Start(e) if e.name() == b"svg" => {}
+            quick_xml::events::Event::End(e) if e.name() == b"svg" => {}
+            quick_xml::events::Event::Start(e) => {
+                writer.write_event(quick_xml::events::Event::Start(rewrite(&e)?))?
+            }
+            quick_xml::events::Event::Empty(e) => {
+                writer.write_event(quick_xml::events::Event::Empty(rewrite(&e)?))?
+            }
+            quick_xml::events::Event::Decl(_)
+            | quick_xml::events::Event::DocType(_)
+            | quick_xml::events::Event::PI(_)
+            | quick_xml::events::Event::Comment(_) => {}
+            quick_xml::events::Event::Eof => break,
+            e => writer.write_event(e)?,
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner().into_inner())?)
+}
+
+/// Turns an SVG into a standalone document, sized in pt after its viewBox.
+pub fn standalone(svg_data: &[u8]) -> Result<String> {
+    let (_, _, width, height) = view_box(svg_data)?;
+    let mut reader = quick_xml::Reader::from_bytes(svg_data);
+    let mut writer = quick_xml::Writer::new(std::io::Cursor::new(vec![]));
+    let mut root = true;
+    loop {
+        match reader.read_event_unbuffered()? {
+            quick_xml::events::Event::Start(e) if root && e.name() == b"svg" => {
+                root = false;
+                let mut new_e = quick_xml::events::BytesStart::owned_name(b"svg".to_vec());
+                let mut has_xmlns = false;
+                for attr in e.attributes() {
+                    let attr = attr?;
+                    match attr.key {
+                        b"width" | b"height" => continue,
+                        b"xmlns" => has_xmlns = true,
+                        _ => {}
+                    }
+                    new_e.push_attribute(attr);
+                }
+                if !has_xmlns {
+                    new_e.push_attribute(("xmlns", "http://www.w3.org/2000/svg"));
+                }
+                new_e.push_attribute(("width", format!("{:.2}pt", width).as_str()));
+                new_e.push_attribute(("height", format!("{:.2}pt", height).as_str()));
+                writer.write_event(quick_xml::events::Event::Start(new_e))?;
+            }
+            quick_xml::events::Event::Eof => break,
+            e => writer.write_event(e)?,
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner().into_inner())?)
+}
+
+/// Normalizes a color to the "#rrggbb" form used by usvg when writing SVGs. Only a few forms are
+/// supported: "black", "white", "#rgb", "#rrggbb" and "rgb(r, g, b)".
+pub fn normalize_color(color: &str) -> Option<String> {
+    let color = color.trim().to_lowercase();
+    let rgb: Vec<u8> = match color.as_str() {
+        "black" => vec![0, 0, 0],
+        "white" => vec![255, 255, 255],
+        hex if hex.starts_with('#') && hex.len() == 4 => hex[1..]
+            .chars()
+            .map(|c| c.to_digit(16).map(|d| d as u8 * 17))
+            .collect::<Option<_>>()?,
+        hex if hex.starts_with('#') && hex.len() == 7 => (1..7)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<_>>()?,
+        rgb if rgb.starts_with("rgb(") && rgb.ends_with(')') => rgb[4..rgb.len() - 1]
+            .split(',')
+            .map(|c| c.trim().parse().ok())
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+    match rgb[..] {
+        [r, g, b] => Some(format!("#{:02x}{:02x}{:02x}", r, g, b)),
+        _ => None,
+    }
+}
+
+/// Applies color rules to an SVG written by usvg.
+///
+/// Fills, strokes and gradient stops matching a rule get its `to` color. Those matching a rule with
+/// a `dark` color also get a class, which a `prefers-color-scheme: dark` media query in a <style>
+/// at the top of the SVG uses to override them.
+pub fn remap_colors(svg_data: &[u8], rules: &[ColorRule]) -> Result<Vec<u8>> {
+    let from = rules
+        .iter()
+        .map(|rule| normalize_color(&rule.from))
+        .collect::<Vec<_>>();
+    let mut reader = quick_xml::Reader::from_bytes(svg_data);
+    let mut writer = quick_xml::Writer::new(std::io::Cursor::new(vec![]));
+    let rewrite = |e: &quick_xml::events::BytesStart| -> Result<quick_xml::events::BytesStart> {
+        let mut new_e = quick_xml::events::BytesStart::owned_name(e.name().to_vec());
+        let mut classes = vec![];
+        for attr in e.attributes() {
+            let attr = attr?;
+            let value = String::from_utf8_lossy(&attr.unescaped_value()?).into_owned();
+            let key = String::from_utf8_lossy(attr.key).into_owned();
+            if key == "class" {
+                classes.push(value);
+                continue;
+            }
+            let idx = match key.as_str() {
+                "fill" | "stroke" | "stop-color" => {
+                    let value = normalize_color(&value);
+                    from.iter()
+                        .position(|from| value.is_some() && *from == value)
+                }
+                _ => None,
+            };
+            match idx {
+                Some(idx) => {
+                    if rules[idx].dark.is_some() {
+                        classes.push(format!("jl-dark-{}-{}", key, idx));
+                    }
+                    let to = rules[idx].to.as_deref().unwrap_or(&value);
+                    new_e.push_attribute((key.as_str(), to));
+                }
+                None => new_e.push_attribute((key.as_str(), value.as_str())),
+            }
+        }
+        if !classes.is_empty() {
+            new_e.push_attribute(("class", classes.join(" ").as_str()));
+        }
+        Ok(new_e)
+    };
+
+    let mut dark_style = String::new();
+    for (idx, rule) in rules.iter().enumerate() {
+        if let Some(dark) = &rule.dark {
+            for property in ["fill", "stroke", "stop-color"] {
+                dark_style.push_str(&format!(".jl-dark-{0}-{1}{{{0}:{2}}}", property, idx, dark));
+            }
+        }
+    }
+    loop {
+        match reader.read_event_unbuffered()? {
+            quick_xml::events::Event::Start(e) if e.name() == b"svg" => {
+                writer.write_event(quick_xml::events::Event::Start(e))?;
+                if !dark_style.is_empty() {
+                    writer.write_event(quick_xml::events::Event::Start(
+                        quick_xml::events::BytesStart::borrowed_name(b"style"),
+                    ))?;
+                    writer.write_event(quick_xml::events::Event::Text(
+                        quick_xml::events::BytesText::from_plain_str(&format!(
+                            "@media (prefers-color-scheme:dark){{{}}}",
+                            dark_style
+                        )),
+                    ))?;
+                    writer.write_event(quick_xml::events::Event::End(
+                        quick_xml::events::BytesEnd::borrowed(b"style"),
+                    ))?;
+                }
+            }
+            quick_xml::events::Event::Start(e) => {
+                writer.write_event(quick_xml::events::Event::Start(rewrite(&e)?))?
+            }
+            quick_xml::events::Event::Empty(e) => {
+                writer.write_event(quick_xml::events::Event::Empty(rewrite(&e)?))?
+            }
+            quick_xml::events::Event::Eof => break,
+            e => writer.write_event(e)?,
+        }
+    }
+    Ok(writer.into_inner().into_inner())
+}
+
 /// Parses raw svg data to a usvg Tree.
 ///
 /// Under DVI/XDV mode, dvisvgm embeds fonts into the svg that unfortunately will not be recognized
@@ -176,3 +452,121 @@ pub fn refine_y_range(bboxes: &[PathBbox], y_min: f64, y_max: f64, tol: f64) ->
     }
     (new_y_min, new_y_max)
 }
+
+/// Shrinks a vertical range to the bboxes that lie entirely within it. The range is left as it is
+/// if there are none.
+pub fn fit_y_range(bboxes: &[PathBbox], y_min: f64, y_max: f64) -> (f64, f64) {
+    let mut fitted: Option<(f64, f64)> = None;
+    for bbox in bboxes {
+        if bbox.top() >= y_min && bbox.bottom() <= y_max {
+            fitted = Some(match fitted {
+                Some((low, high)) => (low.min(bbox.top()), high.max(bbox.bottom())),
+                None => (bbox.top(), bbox.bottom()),
+            });
+        }
+    }
+    fitted.unwrap_or((y_min, y_max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(from: &str, to: Option<&str>, dark: Option<&str>) -> ColorRule {
+        ColorRule {
+            from: from.into(),
+            to: to.map(String::from),
+            dark: dark.map(String::from),
+        }
+    }
+
+    #[test]
+    fn normalizes_supported_colors() {
+        for black in [
+            "#000",
+            "#000000",
+            "black",
+            "BLACK",
+            " rgb(0,0,0) ",
+            "rgb(0, 0, 0)",
+        ] {
+            assert_eq!(
+                normalize_color(black).as_deref(),
+                Some("#000000"),
+                "{}",
+                black
+            );
+        }
+        assert_eq!(normalize_color("white").as_deref(), Some("#ffffff"));
+        assert_eq!(normalize_color("#F0a").as_deref(), Some("#ff00aa"));
+        assert_eq!(normalize_color("#12AbEf").as_deref(), Some("#12abef"));
+        assert_eq!(normalize_color("rgb(1,2,255)").as_deref(), Some("#0102ff"));
+    }
+
+    #[test]
+    fn rejects_unsupported_colors() {
+        for color in [
+            "",
+            "none",
+            "red",
+            "currentColor",
+            "#00",
+            "#0000",
+            "#ggg",
+            "#00000g",
+            "#ééé",
+            "rgb(0,0)",
+            "rgb(0,0,256)",
+            "rgba(0,0,0,1)",
+            "url(#gradient)",
+        ] {
+            assert_eq!(normalize_color(color), None, "{}", color);
+        }
+    }
+
+    #[test]
+    fn remaps_matching_colors_only() {
+        let svg = concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10">"#,
+            r##"<linearGradient id="a"><stop stop-color="#000000"/></linearGradient>"##,
+            r##"<path fill="#000000" stroke="#ff0000" d="M0 0h1"/>"##,
+            r##"<path class="x" fill="none" stroke="#000000" d="M0 1h1"/>"##,
+            r#"<path fill="url(#a)" d="M0 2h1"/>"#,
+            r##"<path fill="#ffffff" d="M0 3h1"/>"##,
+            "</svg>"
+        );
+        let rules = [
+            rule("black", Some("currentColor"), Some("#e0e0e0")),
+            rule("#fff", Some("var(--bg)"), None),
+        ];
+        let remapped = String::from_utf8(remap_colors(svg.as_bytes(), &rules).unwrap()).unwrap();
+        assert_eq!(
+            remapped,
+            concat!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10">"#,
+                "<style>@media (prefers-color-scheme:dark){",
+                ".jl-dark-fill-0{fill:#e0e0e0}.jl-dark-stroke-0{stroke:#e0e0e0}",
+                ".jl-dark-stop-color-0{stop-color:#e0e0e0}}</style>",
+                r#"<linearGradient id="a"><stop stop-color="currentColor" "#,
+                r#"class="jl-dark-stop-color-0"/></linearGradient>"#,
+                r##"<path fill="currentColor" stroke="#ff0000" d="M0 0h1" "##,
+                r#"class="jl-dark-fill-0"/>"#,
+                r#"<path fill="none" stroke="currentColor" d="M0 1h1" "#,
+                r#"class="x jl-dark-stroke-0"/>"#,
+                r#"<path fill="url(#a)" d="M0 2h1"/>"#,
+                r#"<path fill="var(--bg)" d="M0 3h1"/>"#,
+                "</svg>"
+            )
+        );
+    }
+
+    #[test]
+    fn leaves_svgs_without_matches_alone() {
+        let svg = r##"<svg viewBox="0 0 1 1"><path fill="#ff0000" d="M0 0h1"/></svg>"##;
+        let rules = [rule("black", Some("currentColor"), None)];
+        assert_eq!(
+            remap_colors(svg.as_bytes(), &rules).unwrap(),
+            svg.as_bytes()
+        );
+    }
+}
@@ -1,64 +1,263 @@
-#![allow(non_upper_case_globals)]
-#![allow(non_camel_case_types)]
-#![allow(non_snake_case)]
-#![allow(dead_code)]
+//! A reader for SyncTeX files.
+//!
+//! Only the subset of the format needed to locate boxes by input line is supported. A SyncTeX file
+//! looks like this (see `synctex_parser.c` in TeX Live for the reference implementation):
+//!
+//! ```text
+//! SyncTeX Version:1
+//! Input:1:/path/to/source.tex
+//! Output:pdf
+//! Magnification:1000
+//! Unit:1
+//! X Offset:0
+//! Y Offset:0
+//! Content:
+//! !123
+//! {1
+//! [1,12:4736286,4736286:30785863,0,0
+//! (1,14:4736286,5222235:30785863,655360,0
+//! $1,14:5000000,5222235
+//! )
+//! ]
+//! }1
+//! Postamble:
+//! ```
+//!
+//! `{`/`}` delimit pages, `[`/`]` vertical boxes and `(`/`)` horizontal boxes. Other records
+//! (void boxes, rules, kerns, glues, math nodes...) are single lines. Every record carries the tag
+//! of its input file and line, followed by its position and, for boxes, its dimensions.
+//!
+//! Positions are read as they are, in units of `Unit:` sp. `Magnification`, `X Offset` and
+//! `Y Offset` are ignored, unlike in the C reader, which applies them to get viewer coordinates:
+//! documents changing them (e.g. with `\mag`) are not supported.
 
-use std::{ffi::CString, os::raw::c_int, path::Path, hash::Hash};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
 
+use anyhow::{bail, format_err, Context, Result};
+use flate2::read::GzDecoder;
 use ordered_float::OrderedFloat;
 
-include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+/// The tag SyncTeX gives to the main input file.
+const MAIN_INPUT_TAG: u32 = 1;
 
-pub struct Scanner(synctex_scanner_p);
+pub struct Scanner {
+    nodes: Vec<Node>,
+    /// Indices of the nodes for each (tag, line).
+    lines: HashMap<(u32, usize), Vec<usize>>,
+    /// Size of the unit used in the file, in sp.
+    unit: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NodeKind {
+    VBox,
+    HBox,
+    VoidVBox,
+    VoidHBox,
+    Rule,
+    /// Kerns, glues, math nodes and the like. They do not have dimensions of their own.
+    Other,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    kind: NodeKind,
+    page: u32,
+    h: i64,
+    v: i64,
+    width: i64,
+    height: i64,
+    depth: i64,
+    /// Index of the enclosing box, if any.
+    parent: Option<usize>,
+}
 
 impl Scanner {
-    pub fn new(output: impl AsRef<Path>, build_dir: impl AsRef<Path>) -> Self {
-        let output = CString::new(output.as_ref().to_string_lossy().as_bytes()).unwrap();
-        let build_dir = CString::new(build_dir.as_ref().to_string_lossy().as_bytes()).unwrap();
-        Self(unsafe {
-            synctex_scanner_new_with_output_file(output.as_ptr(), build_dir.as_ptr(), 1)
-        })
+    /// Reads the SyncTeX file corresponding to `output` (the PDF/DVI/XDV file) in `build_dir`,
+    /// either compressed or not.
+    pub fn new(output: impl AsRef<Path>, build_dir: impl AsRef<Path>) -> Result<Self> {
+        let stem = output
+            .as_ref()
+            .file_stem()
+            .context("output file has no name")?
+            .to_string_lossy()
+            .into_owned();
+        let plain = build_dir.as_ref().join(format!("{}.synctex", stem));
+        let gz = build_dir.as_ref().join(format!("{}.synctex.gz", stem));
+        let scanner = if plain.exists() {
+            Self::from_reader(BufReader::new(File::open(&plain)?))
+        } else if gz.exists() {
+            Self::from_reader(BufReader::new(GzDecoder::new(File::open(&gz)?)))
+        } else {
+            Err(format_err!("cannot find SyncTeX file {}", plain.display()))
+        };
+        scanner.with_context(|| format!("reading SyncTeX file for {}", output.as_ref().display()))
     }
 
-    pub fn query(&self, line: usize) -> Vec<TeXBox> {
-        fn texpt_to_f64(x: i32) -> OrderedFloat<f64> {
-            (x as f64 / 65536.0).into()
+    /// Parses an uncompressed SyncTeX file.
+    pub fn from_reader(reader: impl BufRead) -> Result<Self> {
+        let mut scanner = Self {
+            nodes: vec![],
+            lines: HashMap::new(),
+            unit: 1.0,
+        };
+        let mut in_content = false;
+        let mut page = 0;
+        // Enclosing boxes of the current record.
+        let mut stack: Vec<usize> = vec![];
+        // Forms (PDF XObjects) are described separately and referenced from the pages. Boxes in them
+        // are not where they appear on the page, so skip them altogether.
+        let mut form_depth = 0usize;
+
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line?;
+            let context = || format!("malformed SyncTeX record on line {}: {}", line_no + 1, line);
+            if !in_content {
+                if let Some(unit) = line.strip_prefix("Unit:") {
+                    scanner.unit = unit.trim().parse().with_context(context)?;
+                } else if line.starts_with("Content:") {
+                    in_content = true;
+                }
+                continue;
+            }
+            if line.starts_with("Postamble:") {
+                break;
+            }
+            let mut chars = line.chars();
+            let (kind, rest) = match chars.next() {
+                Some('<') => {
+                    form_depth += 1;
+                    continue;
+                }
+                Some('>') => {
+                    form_depth = form_depth.saturating_sub(1);
+                    continue;
+                }
+                _ if form_depth > 0 => continue,
+                Some('{') => {
+                    page = chars.as_str().trim().parse().with_context(context)?;
+                    stack.clear();
+                    continue;
+                }
+                Some('}') => {
+                    stack.clear();
+                    continue;
+                }
+                Some(']') | Some(')') => {
+                    stack.pop();
+                    continue;
+                }
+                Some('[') => (NodeKind::VBox, chars.as_str()),
+                Some('(') => (NodeKind::HBox, chars.as_str()),
+                Some('v') => (NodeKind::VoidVBox, chars.as_str()),
+                Some('h') => (NodeKind::VoidHBox, chars.as_str()),
+                Some('r') => (NodeKind::Rule, chars.as_str()),
+                Some('$') | Some('k') | Some('g') | Some('x') => (NodeKind::Other, chars.as_str()),
+                // Inputs declared in the middle of the content, form references, byte offsets...
+                _ => continue,
+            };
+
+            // The format of a record is tag,line[,column]:h,v[:width[,height,depth]].
+            let mut fields = rest.split(':');
+            let mut location = fields.next().unwrap_or_default().split(',');
+            let tag: u32 = location
+                .next()
+                .unwrap_or_default()
+                .parse()
+                .with_context(context)?;
+            let input_line: usize = location
+                .next()
+                .unwrap_or_default()
+                .parse()
+                .with_context(context)?;
+            let numbers = fields
+                .flat_map(|field| field.split(','))
+                .map(|s| s.parse::<i64>())
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(context)?;
+            let number = |i: usize| numbers.get(i).copied().unwrap_or(0);
+            if numbers.len() < 2 {
+                bail!("{}", context());
+            }
+
+            let idx = scanner.nodes.len();
+            scanner.nodes.push(Node {
+                kind,
+                page,
+                h: number(0),
+                v: number(1),
+                width: number(2),
+                height: number(3),
+                depth: number(4),
+                parent: stack.last().copied(),
+            });
+            scanner
+                .lines
+                .entry((tag, input_line))
+                .or_default()
+                .push(idx);
+            if let NodeKind::VBox | NodeKind::HBox = kind {
+                stack.push(idx);
+            }
         }
+        Ok(scanner)
+    }
 
-        unsafe {
-            let name = synctex_scanner_get_name(self.0, 1);
-            let result = synctex_display_query(self.0, name, line as c_int, 0, -1);
-            let mut ret = vec![];
-            if result > 0 {
-                let mut node = synctex_scanner_next_result(self.0);
-                while !node.is_null() {
-                    ret.push(TeXBox {
-                        h: texpt_to_f64(synctex_node_box_h(node)),
-                        v: texpt_to_f64(synctex_node_box_v(node)),
-                        height: texpt_to_f64(synctex_node_box_height(node)),
-                        width: texpt_to_f64(synctex_node_box_width(node)),
-                        depth: texpt_to_f64(synctex_node_box_depth(node)),
-                        page: synctex_node_page(node) as u32,
-                        // ty: String::from(CStr::from_ptr(synctex_node_isa(node)).to_str().unwrap())
-                    });
-                    node = synctex_scanner_next_result(self.0);
+    /// Finds the boxes that come from the given line of the main input file.
+    ///
+    /// Nodes without dimensions of their own (kerns, glues, math nodes...) are represented by the
+    /// horizontal box enclosing them.
+    pub fn query(&self, line: usize) -> Vec<TeXBox> {
+        let mut ret: Vec<TeXBox> = vec![];
+        for &idx in self
+            .lines
+            .get(&(MAIN_INPUT_TAG, line))
+            .map_or(&[][..], |v| &v[..])
+        {
+            let node = &self.nodes[idx];
+            let node = if let NodeKind::Other = node.kind {
+                match self.enclosing_hbox(node) {
+                    Some(parent) => parent,
+                    None => continue,
                 }
+            } else {
+                node
+            };
+            let tb = self.to_box(node);
+            if !ret.contains(&tb) {
+                ret.push(tb);
             }
-            ret
         }
+        ret
     }
 
-    pub fn dump(&self) {
-        unsafe {
-            synctex_scanner_display(self.0);
+    fn enclosing_hbox(&self, node: &Node) -> Option<&Node> {
+        let mut parent = node.parent;
+        while let Some(idx) = parent {
+            let node = &self.nodes[idx];
+            if let NodeKind::HBox = node.kind {
+                return Some(node);
+            }
+            parent = node.parent;
         }
+        None
     }
-}
 
-impl Drop for Scanner {
-    fn drop(&mut self) {
-        unsafe {
-            synctex_scanner_free(self.0);
+    fn to_box(&self, node: &Node) -> TeXBox {
+        let texpt_to_f64 =
+            |x: i64| -> OrderedFloat<f64> { (x as f64 * self.unit / 65536.0).into() };
+        TeXBox {
+            h: texpt_to_f64(node.h),
+            v: texpt_to_f64(node.v),
+            height: texpt_to_f64(node.height),
+            width: texpt_to_f64(node.width),
+            depth: texpt_to_f64(node.depth),
+            page: node.page,
         }
     }
 }
@@ -71,5 +270,80 @@ pub struct TeXBox {
     pub width: OrderedFloat<f64>,
     pub depth: OrderedFloat<f64>,
     pub page: u32,
-    // pub ty: String,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::BufReader, path::PathBuf};
+
+    use super::*;
+
+    fn fixtures() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/synctex")
+    }
+
+    fn nested() -> Scanner {
+        let file = File::open(fixtures().join("nested.synctex")).unwrap();
+        Scanner::from_reader(BufReader::new(file)).unwrap()
+    }
+
+    /// A box with dimensions in pt.
+    fn tex_box(h: f64, v: f64, width: f64, height: f64, depth: f64, page: u32) -> TeXBox {
+        TeXBox {
+            h: h.into(),
+            v: v.into(),
+            width: width.into(),
+            height: height.into(),
+            depth: depth.into(),
+            page,
+        }
+    }
+
+    #[test]
+    fn nested_boxes() {
+        let scanner = nested();
+        assert_eq!(
+            scanner.query(1),
+            [tex_box(0.0, 0.0, 100.0, 100.0, 0.0, 1)]
+        );
+        // The void box on line 2 of another input file is left out.
+        assert_eq!(scanner.query(2), [tex_box(1.0, 2.0, 50.0, 10.0, 2.0, 1)]);
+        assert_eq!(scanner.query(4), [tex_box(3.0, 2.0, 20.0, 10.0, 0.0, 1)]);
+        assert_eq!(scanner.query(5), [tex_box(3.0, 4.0, 10.0, 5.0, 1.0, 1)]);
+        // Boxes on other pages, and their rules.
+        assert_eq!(
+            scanner.query(9),
+            [
+                tex_box(1.0, 1.0, 10.0, 1.0, 0.0, 2),
+                tex_box(1.0, 1.0, 0.5, 1.0, 0.0, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn records_resolve_to_enclosing_hbox() {
+        let scanner = nested();
+        let outer = || tex_box(1.0, 2.0, 50.0, 10.0, 2.0, 1);
+        // A math node directly in the outer hbox.
+        assert_eq!(scanner.query(3), [outer()]);
+        // A kern in an hbox nested in a vbox in the outer hbox.
+        assert_eq!(scanner.query(6), [tex_box(3.0, 4.0, 10.0, 5.0, 1.0, 1)]);
+        // A glue after the nested boxes are closed.
+        assert_eq!(scanner.query(7), [outer()]);
+        // A math node in a vbox only has no box to stand for it.
+        assert_eq!(scanner.query(8), []);
+    }
+
+    #[test]
+    fn forms_are_skipped() {
+        assert_eq!(nested().query(10), []);
+    }
+
+    #[test]
+    fn compressed_with_unit() {
+        let scanner = Scanner::new("units.pdf", fixtures()).unwrap();
+        let hbox = || tex_box(1.0, 2.0, 10.0, 1.0, 0.5, 1);
+        assert_eq!(scanner.query(1), [hbox()]);
+        assert_eq!(scanner.query(2), [hbox()]);
+    }
+}
@@ -0,0 +1,143 @@
+//! Watch mode: re-renders a Markdown file to HTML whenever it changes.
+//!
+//! Pandoc runs twice per change, once to read the Markdown into JSON and once to write the filtered
+//! document. In between, the fragments of the document are compared with those of the previous
+//! run: if neither they nor the configuration changed, which is the case when only prose is edited,
+//! the previous rendering is reused without running LaTeX at all. Since `DontShow` fragments and
+//! the preamble are part of the fragments and the configuration, changing macros does recompile.
+//!
+//! Changes are found by polling the modification times of the input and of the config files (see
+//! [`Overrides::config_files`]). The config files are looked for again on every poll, so that
+//! creating one also triggers a rebuild.
+
+use std::{
+    ffi::OsString,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::config::{Config, Overrides};
+use crate::pandoc;
+use crate::{Collector, Fragment, Rendered};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+/// What the previous run rendered, and from what.
+struct Previous {
+    config: Config,
+    fragments: Vec<Fragment>,
+    rendered: Rendered,
+}
+
+/// Watches `input` and writes it to `output` with Pandoc on every change, until killed.
+/// `pandoc_args` are passed to Pandoc when writing the output, e.g. `--standalone`.
+pub fn watch(
+    input: &Path,
+    output: &Path,
+    pandoc_args: &[String],
+    overrides: &Overrides,
+) -> Result<()> {
+    if !input.exists() {
+        bail!("{} does not exist", input.display());
+    }
+    let mut last_modified = None;
+    let mut previous = None;
+    loop {
+        // A missing $JUST_LATEX_CONFIG is reported by the rebuild.
+        let mut files = overrides.config_files().unwrap_or_default();
+        files.insert(0, input.to_path_buf());
+        // Editors may replace the file, so it may be briefly missing.
+        let modified = files
+            .into_iter()
+            .map(|path| {
+                let modified = path.metadata().and_then(|m| m.modified()).ok();
+                (path, modified)
+            })
+            .collect::<Vec<_>>();
+        if last_modified.as_ref() != Some(&modified) {
+            last_modified = Some(modified);
+            let start = Instant::now();
+            // Errors (e.g. in LaTeX) are reported and the watch goes on, waiting for a fix.
+            match rebuild(input, output, pandoc_args, overrides, &mut previous) {
+                Ok(()) => eprintln!(
+                    "Wrote {} in {}s",
+                    output.display(),
+                    start.elapsed().as_secs_f64()
+                ),
+                Err(e) => eprintln!("Error: {:?}", e),
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn rebuild(
+    input: &Path,
+    output: &Path,
+    pandoc_args: &[String],
+    overrides: &Overrides,
+    previous: &mut Option<Previous>,
+) -> Result<()> {
+    let json = run_pandoc(
+        &[input.as_os_str().into(), "-t".into(), "json".into()],
+        None,
+    )?;
+    let tree: Value = serde_json::from_slice(&json).context("reading the output of pandoc")?;
+    let config = Config::load(&tree, overrides)?;
+    let mut doc = pandoc::read(tree)?;
+
+    let mut collector = Collector::default();
+    pandoc::walk(&mut doc, &mut collector)?;
+    let rendered = match previous {
+        Some(previous)
+            if previous.config == config && previous.fragments == collector.fragments =>
+        {
+            eprintln!("Fragments unchanged, skipping LaTeX");
+            previous.rendered.clone()
+        }
+        _ => crate::render(&config, &collector.fragments)?,
+    };
+    *previous = Some(Previous {
+        config,
+        fragments: collector.fragments.clone(),
+        rendered: rendered.clone(),
+    });
+    collector.apply(&mut doc, rendered)?;
+
+    let mut args = vec![
+        "-f".into(),
+        "json".into(),
+        "-o".into(),
+        output.as_os_str().into(),
+    ];
+    args.extend(pandoc_args.iter().map(Into::into));
+    run_pandoc(&args, Some(&serde_json::to_vec(&doc)?))?;
+    Ok(())
+}
+
+/// Runs pandoc, feeding it `input` on stdin if any, and returns its stdout.
+fn run_pandoc(args: &[OsString], input: Option<&[u8]>) -> Result<Vec<u8>> {
+    let mut child = Command::new("pandoc")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .context("running pandoc")?;
+    let mut stdin = child.stdin.take().unwrap();
+    if let Some(input) = input {
+        stdin.write_all(input)?;
+    }
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("pandoc failed with {}", output.status);
+    }
+    Ok(output.stdout)
+}